@@ -1,18 +1,33 @@
-use std::fs::File;
-use std::io::Read;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
 use std::rc::Rc;
-use std::sync::Mutex;
+use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
+use fnv::FnvHashMap;
+use itertools::Itertools;
+
+use crate::env::{dyn_binding, dyn_def, dyn_get};
+use crate::json::{json_decode, json_encode};
+use crate::printer::{pr_full, pr_seq, str_seq};
+use crate::reader::{read_all, read_edn, read_source, ReadLimits, TagHandlers};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Stream, Sym, Tagged, Vector,
+};
+use crate::types::{
+    _assoc, _dissoc, atom, error, func, hash_map, ordered_map, sorted_map, MalArgs, MalErr, MalMap,
+    MalRet, MalStream, MalVal, MapKind,
+};
 
 macro_rules! fn_t_int_int {
     ($ret:ident, $fn:expr) => {{
@@ -51,16 +66,18 @@ fn symbol(a: MalArgs) -> MalRet {
     }
 }
 
-fn readline(a: MalArgs) -> MalRet {
-    lazy_static! {
-        static ref RL: Mutex<Editor<()>> = Mutex::new(Editor::<()>::new());
+fn gensym(a: MalArgs) -> MalRet {
+    match a.first() {
+        None => Ok(crate::types::gensym("G__")),
+        Some(Str(p)) if !a[0].keyword_q() => Ok(crate::types::gensym(p)),
+        _ => error("gensym: prefix is not Str"),
     }
-    //let mut rl = Editor::<()>::new();
+}
 
+fn readline(rl: &mut Editor<()>, a: MalArgs) -> MalRet {
     match a[0] {
         Str(ref p) => {
-            //match rl.readline(p) {
-            match RL.lock().unwrap().readline(p) {
+            match rl.readline(p) {
                 Ok(mut line) => {
                     // Remove any trailing \n or \r\n
                     if line.ends_with('\n') {
@@ -79,14 +96,697 @@ fn readline(a: MalArgs) -> MalRet {
     }
 }
 
+// I/O failures are thrown as a map so that they can be caught and
+// inspected, e.g.
+//   {:type :io-error :op "slurp" :path "x.txt" :kind :not-found :message "..."}
+fn io_error(op: &str, path: &str, e: io::Error) -> MalErr {
+    let kind = match e.kind() {
+        ErrorKind::NotFound => "not-found",
+        ErrorKind::PermissionDenied => "permission-denied",
+        ErrorKind::AlreadyExists => "already-exists",
+        ErrorKind::InvalidInput => "invalid-input",
+        ErrorKind::InvalidData => "invalid-data",
+        _ => "other",
+    };
+    match ordered_map(vec![
+        kw("type"),
+        kw("io-error"),
+        kw("op"),
+        Str(op.to_string()),
+        kw("path"),
+        Str(path.to_string()),
+        kw("kind"),
+        kw(kind),
+        kw("message"),
+        Str(e.to_string()),
+    ]) {
+        Ok(hm) => ErrMalVal(hm),
+        Err(e) => e,
+    }
+}
+
 fn slurp(f: String) -> MalRet {
+    read_file("slurp", &f).map(Str)
+}
+
+// The contents of the file at path, for the function op
+pub fn read_file(op: &str, path: &str) -> Result<String, MalErr> {
     let mut s = String::new();
-    match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
-        Ok(_) => Ok(Str(s)),
-        Err(e) => error(&e.to_string()),
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => Ok(s),
+        Err(e) => Err(io_error(op, path, e)),
+    }
+}
+
+// Parses the [:append bool] options of spit and writer
+fn append_opt(opts: &[MalVal], op: &str) -> Result<bool, MalErr> {
+    let mut append = false;
+    for (k, v) in opts.iter().tuples() {
+        match k {
+            Str(ref s) if s == "\u{29e}append" => append = truthy(v),
+            _ => {
+                return Err(ErrString(format!(
+                    "{}: unknown option {}",
+                    op,
+                    k.pr_str(true)
+                )))
+            }
+        }
+    }
+    Ok(append)
+}
+
+fn open_write(path: &str, append: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+}
+
+fn spit(a: MalArgs) -> MalRet {
+    let path = match a[0] {
+        Str(ref p) => p.to_string(),
+        _ => return error("spit: path is not Str"),
+    };
+    let append = append_opt(&a[2..], "spit")?;
+    open_write(&path, append)
+        .and_then(|mut f| f.write_all(str_seq(&a[1..2]).as_bytes()))
+        .map_err(|e| io_error("spit", &path, e))?;
+    Ok(Nil)
+}
+
+fn list_dir(p: String) -> MalRet {
+    let mut names = vec![];
+    for entry in fs::read_dir(&p).map_err(|e| io_error("list-dir", &p, e))? {
+        let entry = entry.map_err(|e| io_error("list-dir", &p, e))?;
+        names.push(entry.file_name().to_string_lossy().to_string());
+    }
+    names.sort();
+    Ok(list!(names.into_iter().map(Str).collect()))
+}
+
+fn delete_file(p: String) -> MalRet {
+    let res = if Path::new(&p).is_dir() {
+        fs::remove_dir(&p)
+    } else {
+        fs::remove_file(&p)
+    };
+    res.map_err(|e| io_error("delete-file", &p, e))?;
+    Ok(Nil)
+}
+
+fn rename_file(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Str(from), Str(to)) => {
+            fs::rename(from, to).map_err(|e| io_error("rename-file", from, e))?;
+            Ok(Nil)
+        }
+        _ => error("rename-file: paths are not Str"),
+    }
+}
+
+fn file_size(p: String) -> MalRet {
+    let md = fs::metadata(&p).map_err(|e| io_error("file-size", &p, e))?;
+    Ok(Int(md.len() as i64))
+}
+
+fn file_mtime(p: String) -> MalRet {
+    let mtime = fs::metadata(&p)
+        .and_then(|md| md.modified())
+        .map_err(|e| io_error("file-mtime", &p, e))?;
+    match mtime.duration_since(UNIX_EPOCH) {
+        Ok(d) => Ok(Int(d.as_millis() as i64)),
+        Err(e) => error(&format!("{:?}", e)),
+    }
+}
+
+// (temp-file [prefix [suffix]]) creates a new empty file in the system
+// temp directory and returns its path
+fn temp_file(a: MalArgs) -> MalRet {
+    let arg = |i: usize, default: &str| match a.get(i) {
+        Some(Str(s)) => Ok(s.to_string()),
+        None => Ok(default.to_string()),
+        _ => Err(ErrString("temp-file: prefix/suffix is not Str".to_string())),
+    };
+    let (prefix, suffix) = (arg(0, "mal")?, arg(1, ".tmp")?);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    for attempt in 0..100 {
+        let path = std::env::temp_dir().join(format!(
+            "{}{}-{}{}",
+            prefix,
+            std::process::id(),
+            nanos.wrapping_add(attempt),
+            suffix
+        ));
+        let name = path.to_string_lossy().to_string();
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(Str(name)),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(io_error("temp-file", &name, e)),
+        }
+    }
+    error("temp-file: could not create a unique file")
+}
+
+fn reader(p: String) -> MalRet {
+    let f = File::open(&p).map_err(|e| io_error("reader", &p, e))?;
+    Ok(Stream(Rc::new(RefCell::new(MalStream::Reader {
+        name: p,
+        rdr: Box::new(BufReader::new(f)),
+    }))))
+}
+
+fn writer(a: MalArgs) -> MalRet {
+    let path = match a[0] {
+        Str(ref p) => p.to_string(),
+        _ => return error("writer: path is not Str"),
+    };
+    let append = append_opt(&a[1..], "writer")?;
+    let f = open_write(&path, append).map_err(|e| io_error("writer", &path, e))?;
+    Ok(Stream(Rc::new(RefCell::new(MalStream::Writer {
+        name: path,
+        wtr: Box::new(BufWriter::new(f)),
+    }))))
+}
+
+thread_local! {
+    // Output buffers of the active with-out-str calls, innermost last
+    static OUT_CAPTURE: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+// Everything printed goes to *out*, which may be bound to another stream
+fn out_write(s: &str) -> Result<(), MalErr> {
+    match dyn_get("*out*") {
+        Some(Stream(st)) => stream_write(&mut st.borrow_mut(), s),
+        _ => stdout_write(s),
+    }
+}
+
+// and error output to *err*
+fn err_write(s: &str) -> Result<(), MalErr> {
+    match dyn_get("*err*") {
+        Some(Stream(st)) => stream_write(&mut st.borrow_mut(), s),
+        _ => stream_write(&mut MalStream::Stderr, s),
+    }
+}
+
+// Everything written to standard output goes through here so that
+// with-out-str can capture it.
+fn stdout_write(s: &str) -> Result<(), MalErr> {
+    let captured = OUT_CAPTURE.with(|c| match c.borrow_mut().last_mut() {
+        Some(buf) => {
+            buf.push_str(s);
+            true
+        }
+        None => false,
+    });
+    if !captured {
+        io::stdout()
+            .write_all(s.as_bytes())
+            .map_err(|e| io_error("write", "*out*", e))?;
+    }
+    Ok(())
+}
+
+fn stream_write(st: &mut MalStream, s: &str) -> Result<(), MalErr> {
+    let res = match st {
+        MalStream::Stdout => return stdout_write(s),
+        MalStream::Stderr => io::stderr().write_all(s.as_bytes()),
+        MalStream::Writer { wtr, .. } => wtr.write_all(s.as_bytes()),
+        _ => return Err(ErrString(format!("write: {} is not writable", st.name()))),
+    };
+    res.map_err(|e| io_error("write", st.name(), e))
+}
+
+fn stream_flush(st: &mut MalStream) -> Result<(), MalErr> {
+    let res = match st {
+        MalStream::Stdout => io::stdout().flush(),
+        MalStream::Stderr => io::stderr().flush(),
+        MalStream::Writer { wtr, .. } => wtr.flush(),
+        _ => Ok(()),
+    };
+    res.map_err(|e| io_error("flush", st.name(), e))
+}
+
+// Reads one line without its line terminator, or None at end of input
+fn stream_read_line(st: &mut MalStream) -> Result<Option<String>, MalErr> {
+    let mut line = String::new();
+    let res = match st {
+        MalStream::Stdin => io::stdin().read_line(&mut line),
+        MalStream::Reader { rdr, .. } => rdr.read_line(&mut line),
+        _ => {
+            return Err(ErrString(format!(
+                "read-line: {} is not readable",
+                st.name()
+            )))
+        }
+    };
+    match res.map_err(|e| io_error("read-line", st.name(), e))? {
+        0 => Ok(None),
+        _ => {
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+            }
+            Ok(Some(line))
+        }
+    }
+}
+
+fn with_stream<F>(mv: &MalVal, op: &str, f: F) -> MalRet
+where
+    F: FnOnce(&mut MalStream) -> MalRet,
+{
+    match mv {
+        Stream(st) => f(&mut st.borrow_mut()),
+        _ => error(&format!("{}: argument is not a stream", op)),
+    }
+}
+
+fn write(a: MalArgs) -> MalRet {
+    with_stream(&a[0], "write", |st| {
+        stream_write(st, &str_seq(&a[1..]))?;
+        Ok(Nil)
+    })
+}
+
+fn flush(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(mv) => with_stream(mv, "flush", |st| {
+            stream_flush(st)?;
+            Ok(Nil)
+        }),
+        None => {
+            stream_flush(&mut MalStream::Stdout)?;
+            Ok(Nil)
+        }
+    }
+}
+
+// (read-line [stream]) reads from *in* by default, without any line
+// editing, and returns nil at end of input
+fn read_line(a: MalArgs) -> MalRet {
+    let line = match a.first() {
+        Some(mv) => match mv {
+            Stream(st) => stream_read_line(&mut st.borrow_mut())?,
+            _ => return error("read-line: argument is not a stream"),
+        },
+        None => match dyn_get("*in*") {
+            Some(Stream(st)) => stream_read_line(&mut st.borrow_mut())?,
+            _ => stream_read_line(&mut MalStream::Stdin)?,
+        },
+    };
+    Ok(line.map(Str).unwrap_or(Nil))
+}
+
+// Reads all remaining lines of a stream. mal has no lazy sequences so
+// the whole stream is consumed at once.
+fn line_seq(a: MalArgs) -> MalRet {
+    with_stream(&a[0], "line-seq", |st| {
+        let mut lines = vec![];
+        while let Some(line) = stream_read_line(st)? {
+            lines.push(Str(line));
+        }
+        Ok(list!(lines))
+    })
+}
+
+fn close(a: MalArgs) -> MalRet {
+    with_stream(&a[0], "close", |st| {
+        stream_flush(st)?;
+        let name = st.name().to_string();
+        *st = MalStream::Closed { name };
+        Ok(Nil)
+    })
+}
+
+// (with-out-str* f) calls f and returns everything it printed to *out*
+// as a string. The with-out-str macro wraps its body in such a thunk.
+// *out* is bound to standard output, where the capture happens, for the
+// call.
+fn with_out_str(a: MalArgs) -> MalRet {
+    OUT_CAPTURE.with(|c| c.borrow_mut().push(String::new()));
+    let out = vec![("*out*".to_string(), stream(MalStream::Stdout))];
+    let res = dyn_binding(out, || a[0].apply(vec![]));
+    let out = OUT_CAPTURE.with(|c| c.borrow_mut().pop().unwrap_or_default());
+    res?;
+    Ok(Str(out))
+}
+
+fn stream(st: MalStream) -> MalVal {
+    Stream(Rc::new(RefCell::new(st)))
+}
+
+// process and OS
+
+fn kw(s: &str) -> MalVal {
+    Str(format!("\u{29e}{}", s))
+}
+
+fn env_map() -> MalVal {
+    let mut hm = MalMap::new(MapKind::Hash);
+    for (k, v) in std::env::vars() {
+        hm.insert(k, Str(v));
+    }
+    Hash(Rc::new(hm), Rc::new(Nil))
+}
+
+fn getenv(k: String) -> MalRet {
+    match std::env::var(&k) {
+        Ok(v) => Ok(Str(v)),
+        Err(_) => Ok(Nil),
+    }
+}
+
+fn exit(a: MalArgs) -> MalRet {
+    let code = match a.first() {
+        Some(Int(i)) => match i32::try_from(*i) {
+            Ok(code) => code,
+            Err(_) => return error(&format!("exit: status {} is out of range", i)),
+        },
+        None | Some(Nil) => 0,
+        _ => return error("exit: status is not Int"),
+    };
+    let _ = io::stdout().flush();
+    std::process::exit(code)
+}
+
+fn cwd(_a: MalArgs) -> MalRet {
+    match std::env::current_dir() {
+        Ok(d) => Ok(Str(d.to_string_lossy().to_string())),
+        Err(e) => Err(io_error("cwd", ".", e)),
+    }
+}
+
+fn chdir(p: String) -> MalRet {
+    std::env::set_current_dir(&p).map_err(|e| io_error("chdir", &p, e))?;
+    Ok(Nil)
+}
+
+fn hostname(_a: MalArgs) -> MalRet {
+    let name = match fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(n) => n,
+        Err(_) => match Command::new("hostname").output() {
+            Ok(out) => String::from_utf8_lossy(&out.stdout).to_string(),
+            Err(e) => return Err(io_error("hostname", "hostname", e)),
+        },
+    };
+    Ok(Str(name.trim().to_string()))
+}
+
+// Builds the Command for sh/sh-stream from
+//   cmd arg* [:in str] [:env {name val ...}] [:dir path]
+// and returns it with the stdin text, if any.
+fn sh_command(a: &[MalVal], op: &str) -> Result<(Command, Option<String>), MalErr> {
+    let nargs = a.iter().position(|mv| mv.keyword_q()).unwrap_or(a.len());
+    let mut argv = vec![];
+    for mv in a[..nargs].iter() {
+        match mv {
+            Str(s) => argv.push(s.to_string()),
+            _ => return Err(ErrString(format!("{}: command args must be Str", op))),
+        }
+    }
+    if argv.is_empty() {
+        return Err(ErrString(format!("{}: missing command", op)));
+    }
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..]);
+    let mut input = None;
+    if !(a.len() - nargs).is_multiple_of(2) {
+        return Err(ErrString(format!("{}: odd number of option args", op)));
+    }
+    for (k, v) in a[nargs..].iter().tuples() {
+        match (k, v) {
+            (Str(k), Str(s)) if k == "\u{29e}in" => input = Some(s.to_string()),
+            (Str(k), Str(d)) if k == "\u{29e}dir" => {
+                cmd.current_dir(d);
+            }
+            (Str(k), Hash(hm, _)) if k == "\u{29e}env" => {
+                for (name, val) in hm.iter() {
+                    let name = name.trim_start_matches('\u{29e}');
+                    match val {
+                        Str(s) => cmd.env(name, s),
+                        _ => cmd.env(name, str_seq(std::slice::from_ref(val))),
+                    };
+                }
+            }
+            _ => {
+                return Err(ErrString(format!(
+                    "{}: invalid option {} {}",
+                    op,
+                    k.pr_str(true),
+                    v.pr_str(true)
+                )))
+            }
+        }
+    }
+    Ok((cmd, input))
+}
+
+fn exit_status(status: ExitStatus) -> MalVal {
+    match status.code() {
+        Some(c) => Int(c as i64),
+        None => Nil, // killed by a signal
+    }
+}
+
+// (sh cmd arg* opts*) runs a command to completion and returns
+// {:exit status :out stdout :err stderr}
+fn sh(a: MalArgs) -> MalRet {
+    let (mut cmd, input) = sh_command(&a, "sh")?;
+    let name = cmd.get_program().to_string_lossy().to_string();
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io_error("sh", &name, e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // write from a thread so a child that fills its output pipes
+        // before reading all of stdin cannot deadlock us
+        thread::spawn(move || stdin.write_all(input.as_bytes()));
+    }
+    let out = child
+        .wait_with_output()
+        .map_err(|e| io_error("sh", &name, e))?;
+    ordered_map(vec![
+        kw("exit"),
+        exit_status(out.status),
+        kw("out"),
+        Str(String::from_utf8_lossy(&out.stdout).to_string()),
+        kw("err"),
+        Str(String::from_utf8_lossy(&out.stderr).to_string()),
+    ])
+}
+
+// (sh-stream f cmd arg* opts*) is sh for long-running commands: f is
+// called with each line of stdout as soon as it is written, and the
+// result is {:exit status :err stderr}.
+fn sh_stream(a: MalArgs) -> MalRet {
+    if a.len() < 2 {
+        return error("sh-stream: expects a function and a command");
+    }
+    let (mut cmd, input) = sh_command(&a[1..], "sh-stream")?;
+    let name = cmd.get_program().to_string_lossy().to_string();
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io_error("sh-stream", &name, e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        thread::spawn(move || stdin.write_all(input.as_bytes()));
+    }
+    let err_thread = child.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut s = String::new();
+            let _ = stderr.read_to_string(&mut s);
+            s
+        })
+    });
+    let mut res = Ok(Nil);
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            res = line
+                .map_err(|e| io_error("sh-stream", &name, e))
+                .and_then(|l| a[0].apply(vec![Str(l)]));
+            if res.is_err() {
+                let _ = child.kill();
+                break;
+            }
+        }
+    }
+    let status = child.wait().map_err(|e| io_error("sh-stream", &name, e))?;
+    let err = err_thread.and_then(|t| t.join().ok()).unwrap_or_default();
+    res?;
+    ordered_map(vec![kw("exit"), exit_status(status), kw("err"), Str(err)])
+}
+
+// (read-string s [file]): file names the source s was read from, which
+// is recorded in the position metadata of the lists read
+fn read_string(a: MalArgs) -> MalRet {
+    match (&a[0], a.get(1)) {
+        (Str(s), None) | (Str(s), Some(Nil)) => read_source(s, None, ReadLimits::current()?),
+        (Str(s), Some(Str(f))) => read_source(s, Some(f), ReadLimits::current()?),
+        _ => error("read-string: expecting (str [str]) args"),
+    }
+}
+
+// Prefixes an error message with the name of the function it came from
+fn prefix_error(name: &str, e: MalErr) -> MalErr {
+    match e {
+        ErrString(s) => ErrString(format!("{}: {}", name, s)),
+        e => e,
+    }
+}
+
+// (read-all-string s [file]): all the forms in s, as a list
+fn read_all_string(a: MalArgs) -> MalRet {
+    let forms = match (&a[0], a.get(1)) {
+        (Str(s), None) | (Str(s), Some(Nil)) => read_all(s, None, ReadLimits::current()?),
+        (Str(s), Some(Str(f))) => read_all(s, Some(f), ReadLimits::current()?),
+        _ => return error("read-all-string: expecting (str [str]) args"),
+    };
+    Ok(list!(forms.map_err(|e| prefix_error("read-all-string", e))?))
+}
+
+// EDN (extensible data notation)
+
+// (edn/read-string s [{:readers {tag f ...} :default f}]): the :readers
+// handlers are added to the built-in #inst and #uuid ones; tags may be
+// given as strings or keywords.
+fn edn_read_string(a: MalArgs) -> MalRet {
+    let s = match a[0] {
+        Str(ref s) => s,
+        _ => return error("edn/read-string: expecting (str) arg"),
+    };
+    let mut tags = TagHandlers::default();
+    match a.get(1) {
+        None | Some(Nil) => {}
+        Some(Hash(opts, _)) => {
+            match opts.get("\u{29e}readers") {
+                None | Some(Nil) => {}
+                Some(Hash(readers, _)) => {
+                    for (tag, f) in readers.iter() {
+                        let tag = tag.trim_start_matches('\u{29e}').to_string();
+                        tags.tags.insert(tag, f.clone());
+                    }
+                }
+                Some(_) => return error("edn/read-string: :readers must be a map"),
+            }
+            match opts.get("\u{29e}default") {
+                None | Some(Nil) => {}
+                Some(f) => tags.default = Some(f.clone()),
+            }
+        }
+        Some(_) => return error("edn/read-string: options must be a map"),
+    }
+    ReadLimits::current()
+        .and_then(|limits| read_edn(s, &tags, limits))
+        .map_err(|e| prefix_error("edn/read-string", e))
+}
+
+fn edn_write_string(a: MalArgs) -> MalRet {
+    a[0].edn_str()
+        .map(Str)
+        .map_err(|e| prefix_error("edn/write-string", e))
+}
+
+// (pprint-str val [width]) pretty-prints val to a string, by default
+// within 80 columns
+fn pprint_str(a: MalArgs) -> MalRet {
+    let width = match a.get(1) {
+        None | Some(Nil) => 80,
+        Some(w) => int_arg(w, "pprint-str")?.max(0) as usize,
+    };
+    Ok(Str(a[0].pprint_str(width)))
+}
+
+fn tagged_literal(a: MalArgs) -> MalRet {
+    match a[0] {
+        Sym(ref tag) => Ok(Tagged(tag.to_string(), Rc::new(a[1].clone()))),
+        Str(ref tag) if !a[0].keyword_q() => Ok(Tagged(tag.to_string(), Rc::new(a[1].clone()))),
+        _ => error("tagged-literal: tag must be a symbol or string"),
     }
 }
 
+// The form a function was defined by: its def! or defmacro! form once
+// named, else its fn* form. Native functions have no source.
+fn source(a: MalArgs) -> MalRet {
+    match a[0] {
+        MalFunc { ref info, .. } => Ok(info.form.clone()),
+        Func(_, _) => Ok(Nil),
+        _ => error("source: expecting a function"),
+    }
+}
+
+// {:name :params :arity :variadic :macro :file :line}, where arity is the
+// number of required params; unknown entries (such as the params of a
+// native function) are nil
+fn fn_info(a: MalArgs) -> MalRet {
+    let opt = |o: Option<MalVal>| o.unwrap_or(Nil);
+    let (name, params, is_macro, file, line) = match a[0] {
+        MalFunc {
+            ref info,
+            ref params,
+            is_macro,
+            ..
+        } => (
+            opt(info.name.clone().map(Str)),
+            (**params).clone(),
+            is_macro,
+            opt(info.file.clone().map(Str)),
+            opt(info.line.map(Int)),
+        ),
+        Func(ref nf, _) => (Str(nf.name.clone()), Nil, false, Nil, Nil),
+        _ => return error("fn-info: expecting a function"),
+    };
+    let (arity, variadic) = match params {
+        List(ref p, _) | Vector(ref p, _) => {
+            match p.iter().position(|s| s == &Sym("&".to_string())) {
+                Some(i) => (Int(i as i64), Bool(true)),
+                None => (Int(p.len() as i64), Bool(false)),
+            }
+        }
+        _ => (Nil, Nil),
+    };
+    let params = match params {
+        List(p, _) | Vector(p, _) => vector!(p.to_vec()),
+        mv => mv,
+    };
+    ordered_map(vec![
+        kw("name"),
+        name,
+        kw("params"),
+        params,
+        kw("arity"),
+        arity,
+        kw("variadic"),
+        variadic,
+        kw("macro"),
+        Bool(is_macro),
+        kw("file"),
+        file,
+        kw("line"),
+        line,
+    ])
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -104,6 +804,9 @@ fn get(a: MalArgs) -> MalRet {
             Some(mv) => Ok(mv.clone()),
             None => Ok(Nil),
         },
+        (Tagged(ref tag, _), Str(ref s)) if s == "\u{29e}tag" => Ok(Sym(tag.to_string())),
+        (Tagged(_, ref form), Str(ref s)) if s == "\u{29e}form" => Ok((**form).clone()),
+        (Tagged(_, _), _) => Ok(Nil),
         _ => error("illegal get args"),
     }
 }
@@ -138,7 +841,7 @@ fn keys(a: MalArgs) -> MalRet {
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect())),
         _ => error("keys requires Hash Map"),
     }
 }
@@ -179,7 +882,7 @@ fn nth(a: MalArgs) -> MalRet {
 
 fn first(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
+        List(ref seq, _) | Vector(ref seq, _) if seq.is_empty() => Ok(Nil),
         List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
         Nil => Ok(Nil),
         _ => error("invalid args to first"),
@@ -205,7 +908,7 @@ fn apply(a: MalArgs) -> MalRet {
         List(ref v, _) | Vector(ref v, _) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend_from_slice(v);
             f.apply(fargs)
         }
         _ => error("apply called with non-seq"),
@@ -213,26 +916,25 @@ fn apply(a: MalArgs) -> MalRet {
 }
 
 fn map(a: MalArgs) -> MalRet {
-    match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let mut res = vec![];
-            for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
-            }
-            Ok(list!(res))
-        }
-        _ => error("map called with non-seq"),
+    if a.len() < 2 {
+        return error("map requires a function and at least one seq");
     }
+    let seqs = a[1..]
+        .iter()
+        .map(|s| seq_vec(s, "map"))
+        .collect::<Result<Vec<MalArgs>, MalErr>>()?;
+    let len = seqs.iter().map(|s| s.len()).min().unwrap_or(0);
+    let mut res = vec![];
+    for i in 0..len {
+        res.push(a[0].apply(seqs.iter().map(|s| s[i].clone()).collect())?)
+    }
+    Ok(list!(res))
 }
 
 fn conj(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
+            let sl = a[1..].iter().rev().cloned().collect::<Vec<MalVal>>();
             Ok(list!([&sl[..], v].concat()))
         }
         Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
@@ -242,9 +944,9 @@ fn conj(a: MalArgs) -> MalRet {
 
 fn seq(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
+        List(ref v, _) | Vector(ref v, _) if v.is_empty() => Ok(Nil),
         List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
-        Str(ref s) if s.len() == 0 => Ok(Nil),
+        Str(ref s) if s.is_empty() => Ok(Nil),
         Str(ref s) if !a[0].keyword_q() => {
             Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
         }
@@ -253,14 +955,410 @@ fn seq(a: MalArgs) -> MalRet {
     }
 }
 
+// sequence library
+
+fn seq_vec(mv: &MalVal, name: &str) -> Result<MalArgs, MalErr> {
+    match mv {
+        List(v, _) | Vector(v, _) => Ok(v.to_vec()),
+        Hash(hm, _) => Ok(hm
+            .iter()
+            .map(|(k, v)| vector![Str(k.to_string()), v.clone()])
+            .collect()),
+        Str(s) if !mv.keyword_q() => Ok(s.chars().map(|c| Str(c.to_string())).collect()),
+        Nil => Ok(vec![]),
+        _ => Err(ErrString(format!("{} called with non-seq", name))),
+    }
+}
+
+fn int_arg(mv: &MalVal, name: &str) -> Result<i64, MalErr> {
+    match mv {
+        Int(i) => Ok(*i),
+        _ => Err(ErrString(format!("{} expects an integer", name))),
+    }
+}
+
+fn truthy(mv: &MalVal) -> bool {
+    !matches!(mv, Nil | Bool(false))
+}
+
+fn filter(a: MalArgs) -> MalRet {
+    let mut res = vec![];
+    for mv in seq_vec(&a[1], "filter")? {
+        if truthy(&a[0].apply(vec![mv.clone()])?) {
+            res.push(mv);
+        }
+    }
+    Ok(list!(res))
+}
+
+fn remove(a: MalArgs) -> MalRet {
+    let mut res = vec![];
+    for mv in seq_vec(&a[1], "remove")? {
+        if !truthy(&a[0].apply(vec![mv.clone()])?) {
+            res.push(mv);
+        }
+    }
+    Ok(list!(res))
+}
+
+fn reduce(a: MalArgs) -> MalRet {
+    let (mut acc, items) = match a.len() {
+        2 => {
+            let mut items = seq_vec(&a[1], "reduce")?;
+            if items.is_empty() {
+                return a[0].apply(vec![]);
+            }
+            (items.remove(0), items)
+        }
+        3 => (a[1].clone(), seq_vec(&a[2], "reduce")?),
+        _ => return error("reduce expects 2 or 3 args"),
+    };
+    for mv in items {
+        acc = a[0].apply(vec![acc, mv])?;
+    }
+    Ok(acc)
+}
+
+fn range(a: MalArgs) -> MalRet {
+    let (start, end, step) = match a.len() {
+        1 => (0, int_arg(&a[0], "range")?, 1),
+        2 => (int_arg(&a[0], "range")?, int_arg(&a[1], "range")?, 1),
+        3 => (
+            int_arg(&a[0], "range")?,
+            int_arg(&a[1], "range")?,
+            int_arg(&a[2], "range")?,
+        ),
+        _ => return error("range expects 1 to 3 args"),
+    };
+    if step == 0 {
+        return error("range: step must not be zero");
+    }
+    let mut res = vec![];
+    let mut i = start;
+    while (step > 0 && i < end) || (step < 0 && i > end) {
+        res.push(Int(i));
+        i = match i.checked_add(step) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(list!(res))
+}
+
+fn take(a: MalArgs) -> MalRet {
+    let n = int_arg(&a[0], "take")?.max(0) as usize;
+    Ok(list!(seq_vec(&a[1], "take")?.into_iter().take(n).collect()))
+}
+
+fn drop(a: MalArgs) -> MalRet {
+    let n = int_arg(&a[0], "drop")?.max(0) as usize;
+    Ok(list!(seq_vec(&a[1], "drop")?.into_iter().skip(n).collect()))
+}
+
+fn take_while(a: MalArgs) -> MalRet {
+    let mut res = vec![];
+    for mv in seq_vec(&a[1], "take-while")? {
+        if !truthy(&a[0].apply(vec![mv.clone()])?) {
+            break;
+        }
+        res.push(mv);
+    }
+    Ok(list!(res))
+}
+
+fn drop_while(a: MalArgs) -> MalRet {
+    let v = seq_vec(&a[1], "drop-while")?;
+    let mut i = 0;
+    while i < v.len() && truthy(&a[0].apply(vec![v[i].clone()])?) {
+        i += 1;
+    }
+    Ok(list!(v[i..].to_vec()))
+}
+
+fn reverse(a: MalArgs) -> MalRet {
+    let mut v = seq_vec(&a[0], "reverse")?;
+    v.reverse();
+    Ok(list!(v))
+}
+
+// Stable merge sort that lets the comparison fail (user comparators
+// may throw). std's sort_by cannot propagate errors.
+fn sort_vec<T, F>(mut v: Vec<T>, cmp: &mut F) -> Result<Vec<T>, MalErr>
+where
+    F: FnMut(&T, &T) -> Result<Ordering, MalErr>,
+{
+    if v.len() <= 1 {
+        return Ok(v);
+    }
+    let right = v.split_off(v.len() / 2);
+    let left = sort_vec(v, cmp)?;
+    let right = sort_vec(right, cmp)?;
+    let mut res = Vec::with_capacity(left.len() + right.len());
+    let mut l = left.into_iter().peekable();
+    let mut r = right.into_iter().peekable();
+    while let (Some(x), Some(y)) = (l.peek(), r.peek()) {
+        if cmp(y, x)? == Ordering::Less {
+            res.extend(r.next());
+        } else {
+            res.extend(l.next());
+        }
+    }
+    res.extend(l);
+    res.extend(r);
+    Ok(res)
+}
+
+// A comparator either returns an integer (negative, zero, positive)
+// or is a boolean "less than" predicate.
+fn fn_compare(f: &MalVal, x: &MalVal, y: &MalVal) -> Result<Ordering, MalErr> {
+    match f.apply(vec![x.clone(), y.clone()])? {
+        Int(i) => Ok(i.cmp(&0)),
+        r if truthy(&r) => Ok(Ordering::Less),
+        _ if truthy(&f.apply(vec![y.clone(), x.clone()])?) => Ok(Ordering::Greater),
+        _ => Ok(Ordering::Equal),
+    }
+}
+
+fn sort(a: MalArgs) -> MalRet {
+    match a.len() {
+        1 => Ok(list!(sort_vec(
+            seq_vec(&a[0], "sort")?,
+            &mut |x: &MalVal, y: &MalVal| Ok(x.compare(y))
+        )?)),
+        2 => Ok(list!(sort_vec(
+            seq_vec(&a[1], "sort")?,
+            &mut |x: &MalVal, y: &MalVal| fn_compare(&a[0], x, y)
+        )?)),
+        _ => error("sort expects 1 or 2 args"),
+    }
+}
+
+fn sort_by(a: MalArgs) -> MalRet {
+    let (keyfn, cmp, coll) = match a.len() {
+        2 => (&a[0], None, &a[1]),
+        3 => (&a[0], Some(&a[1]), &a[2]),
+        _ => return error("sort-by expects 2 or 3 args"),
+    };
+    let mut keyed = vec![];
+    for mv in seq_vec(coll, "sort-by")? {
+        keyed.push((keyfn.apply(vec![mv.clone()])?, mv));
+    }
+    let sorted = sort_vec(
+        keyed,
+        &mut |(kx, _): &(MalVal, MalVal), (ky, _): &(MalVal, MalVal)| match cmp {
+            Some(f) => fn_compare(f, kx, ky),
+            None => Ok(kx.compare(ky)),
+        },
+    )?;
+    Ok(list!(sorted.into_iter().map(|(_, mv)| mv).collect()))
+}
+
+// hash-map keys are strings, so group-by and frequencies key a value
+// that isn't one (or a keyword) by its printed form: 1 by "1", true by
+// "true". Two distinct values with the same key, such as true and
+// "true", are an error rather than being merged.
+fn map_key(mv: &MalVal, seen: &mut FnvHashMap<String, MalVal>, op: &str) -> Result<String, MalErr> {
+    let k = match mv {
+        Str(s) => s.to_string(),
+        _ => pr_full(mv),
+    };
+    match seen.get(&k) {
+        Some(prev) if prev.compare(mv) != Ordering::Equal => Err(ErrString(format!(
+            "{}: {} and {} have the same key {:?}",
+            op,
+            prev.pr_str(true),
+            mv.pr_str(true),
+            k
+        ))),
+        Some(_) => Ok(k),
+        None => {
+            seen.insert(k.clone(), mv.clone());
+            Ok(k)
+        }
+    }
+}
+
+fn group_by(a: MalArgs) -> MalRet {
+    let mut seen = FnvHashMap::default();
+    let mut groups: FnvHashMap<String, MalArgs> = FnvHashMap::default();
+    for mv in seq_vec(&a[1], "group-by")? {
+        let k = map_key(&a[0].apply(vec![mv.clone()])?, &mut seen, "group-by")?;
+        groups.entry(k).or_default().push(mv);
+    }
+    let mut hm = MalMap::new(MapKind::Hash);
+    for (k, v) in groups {
+        hm.insert(k, vector!(v));
+    }
+    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+}
+
+fn frequencies(a: MalArgs) -> MalRet {
+    let mut seen = FnvHashMap::default();
+    let mut freqs = MalMap::new(MapKind::Hash);
+    for mv in seq_vec(&a[0], "frequencies")? {
+        let k = map_key(&mv, &mut seen, "frequencies")?;
+        let n = match freqs.get(&k) {
+            Some(Int(n)) => n + 1,
+            _ => 1,
+        };
+        freqs.insert(k, Int(n));
+    }
+    Ok(Hash(Rc::new(freqs), Rc::new(Nil)))
+}
+
+fn partition(a: MalArgs) -> MalRet {
+    let (n, step, pad, coll) = match a.len() {
+        2 => (&a[0], &a[0], None, &a[1]),
+        3 => (&a[0], &a[1], None, &a[2]),
+        4 => (&a[0], &a[1], Some(&a[2]), &a[3]),
+        _ => return error("partition expects 2 to 4 args"),
+    };
+    let (n, step) = (int_arg(n, "partition")?, int_arg(step, "partition")?);
+    if n <= 0 || step <= 0 {
+        return error("partition: size and step must be positive");
+    }
+    let (n, step) = (n as usize, step as usize);
+    let v = seq_vec(coll, "partition")?;
+    let mut res = vec![];
+    let mut i = 0;
+    while i < v.len() {
+        if i + n <= v.len() {
+            res.push(list!(v[i..i + n].to_vec()));
+        } else {
+            if let Some(pad) = pad {
+                let mut part = v[i..].to_vec();
+                let missing = n - part.len();
+                part.extend(seq_vec(pad, "partition")?.into_iter().take(missing));
+                res.push(list!(part));
+            }
+            break;
+        }
+        i += step;
+    }
+    Ok(list!(res))
+}
+
+fn interleave(a: MalArgs) -> MalRet {
+    let seqs = a
+        .iter()
+        .map(|s| seq_vec(s, "interleave"))
+        .collect::<Result<Vec<MalArgs>, MalErr>>()?;
+    let len = seqs.iter().map(|s| s.len()).min().unwrap_or(0);
+    let mut res = vec![];
+    for i in 0..len {
+        res.extend(seqs.iter().map(|s| s[i].clone()));
+    }
+    Ok(list!(res))
+}
+
+fn distinct(a: MalArgs) -> MalRet {
+    let mut res: MalArgs = vec![];
+    for mv in seq_vec(&a[0], "distinct")? {
+        if !res.contains(&mv) {
+            res.push(mv);
+        }
+    }
+    Ok(list!(res))
+}
+
+fn some(a: MalArgs) -> MalRet {
+    for mv in seq_vec(&a[1], "some")? {
+        let r = a[0].apply(vec![mv])?;
+        if truthy(&r) {
+            return Ok(r);
+        }
+    }
+    Ok(Nil)
+}
+
+fn every_q(a: MalArgs) -> MalRet {
+    for mv in seq_vec(&a[1], "every?")? {
+        if !truthy(&a[0].apply(vec![mv])?) {
+            return Ok(Bool(false));
+        }
+    }
+    Ok(Bool(true))
+}
+
+fn last(a: MalArgs) -> MalRet {
+    Ok(seq_vec(&a[0], "last")?.pop().unwrap_or(Nil))
+}
+
+fn butlast(a: MalArgs) -> MalRet {
+    let mut v = seq_vec(&a[0], "butlast")?;
+    if v.len() <= 1 {
+        return Ok(Nil);
+    }
+    v.pop();
+    Ok(list!(v))
+}
+
+fn compare(a: MalArgs) -> MalRet {
+    Ok(Int(match a[0].compare(&a[1]) {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }))
+}
+
+// Returns the arg whose key is greatest (max-key) or least (min-key)
+// under compare; on ties the last such arg wins.
+fn extreme_key(a: MalArgs, name: &str, want: Ordering) -> MalRet {
+    if a.len() < 2 {
+        return error(&format!(
+            "{} expects a key function and at least one arg",
+            name
+        ));
+    }
+    let mut best = a[1].clone();
+    let mut best_key = a[0].apply(vec![best.clone()])?;
+    for mv in a[2..].iter() {
+        let k = a[0].apply(vec![mv.clone()])?;
+        if k.compare(&best_key) != want.reverse() {
+            best = mv.clone();
+            best_key = k;
+        }
+    }
+    Ok(best)
+}
+
+// Gives each builtin its name in ns, so that it prints as #<fn name>
+fn named(ns: Vec<(&'static str, MalVal)>) -> Vec<(&'static str, MalVal)> {
+    ns.into_iter()
+        .map(|(k, mut v)| {
+            if let Func(ref mut nf, _) = v {
+                if let Some(nf) = Rc::get_mut(nf) {
+                    nf.name = k.to_string();
+                }
+            }
+            (k, v)
+        })
+        .collect()
+}
+
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
+    // the printer settings are dynamic so that pr-str, prn etc. see them
+    dyn_def("*print-length*", Nil);
+    dyn_def("*print-level*", Nil);
+    // and the reader's settings
+    dyn_def("*host-language*", Str("rust".to_string()));
+    dyn_def("*reader-features*", vector![]);
+    dyn_def("*reader-limits*", Nil);
+    // the file being loaded by load-file
+    dyn_def("*file*", Nil);
+    // where print, println, prn etc. write, eprintln writes and
+    // read-line reads
+    dyn_def("*out*", stream(MalStream::Stdout));
+    dyn_def("*err*", stream(MalStream::Stderr));
+    dyn_def("*in*", stream(MalStream::Stdin));
+    named(vec![
         ("=", func(|a| Ok(Bool(a[0] == a[1])))),
         ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
         ("nil?", func(fn_is_type!(Nil))),
         ("true?", func(fn_is_type!(Bool(true)))),
         ("false?", func(fn_is_type!(Bool(false)))),
         ("symbol", func(symbol)),
+        ("gensym", func(gensym)),
         ("symbol?", func(fn_is_type!(Sym(_)))),
         (
             "string?",
@@ -281,24 +1379,81 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
             func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
         ),
         ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
+        ("str", func(|a| Ok(Str(str_seq(&a))))),
         (
             "prn",
             func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
+                out_write(&format!("{}\n", pr_seq(&a, true, "", "", " ")))?;
                 Ok(Nil)
             }),
         ),
         (
             "println",
             func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
+                out_write(&format!("{}\n", pr_seq(&a, false, "", "", " ")))?;
                 Ok(Nil)
             }),
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
+        (
+            "print",
+            func(|a| {
+                out_write(&pr_seq(&a, false, "", "", " "))?;
+                Ok(Nil)
+            }),
+        ),
+        (
+            "eprintln",
+            func(|a| {
+                err_write(&format!("{}\n", pr_seq(&a, false, "", "", " ")))?;
+                Ok(Nil)
+            }),
+        ),
+        ("flush", func(flush)),
+        ("read-line", func(read_line)),
+        ("with-out-str*", func(with_out_str)),
+        ("read-string", func(read_string)),
+        ("read-all-string", func(read_all_string)),
+        ("json-encode", func(json_encode)),
+        ("json-decode", func(json_decode)),
+        ("pprint-str", func(pprint_str)),
+        ("edn/read-string", func(edn_read_string)),
+        ("edn/write-string", func(edn_write_string)),
+        ("tagged-literal", func(tagged_literal)),
+        ("source", func(source)),
+        ("fn-info", func(fn_info)),
+        ("readline", {
+            let rl = RefCell::new(Editor::<()>::new());
+            func(move |a| readline(&mut rl.borrow_mut(), a))
+        }),
         ("slurp", func(fn_str!(|f| { slurp(f) }))),
+        ("spit", func(spit)),
+        (
+            "file-exists?",
+            func(fn_str!(|p| { Ok(Bool(Path::new(&p).exists())) })),
+        ),
+        (
+            "directory?",
+            func(fn_str!(|p| { Ok(Bool(Path::new(&p).is_dir())) })),
+        ),
+        ("list-dir", func(fn_str!(|p| { list_dir(p) }))),
+        (
+            "mkdir",
+            func(fn_str!(|p: String| {
+                fs::create_dir_all(&p).map_err(|e| io_error("mkdir", &p, e))?;
+                Ok(Nil)
+            })),
+        ),
+        ("delete-file", func(fn_str!(|p| { delete_file(p) }))),
+        ("rename-file", func(rename_file)),
+        ("file-size", func(fn_str!(|p| { file_size(p) }))),
+        ("file-mtime", func(fn_str!(|p| { file_mtime(p) }))),
+        ("temp-file", func(temp_file)),
+        ("reader", func(fn_str!(|p| { reader(p) }))),
+        ("writer", func(writer)),
+        ("write", func(write)),
+        ("line-seq", func(line_seq)),
+        ("close", func(close)),
+        ("stream?", func(fn_is_type!(Stream(_)))),
         ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
         ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
         (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
@@ -308,13 +1463,29 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
         ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
         ("time-ms", func(time_ms)),
+        ("*ENV*", env_map()),
+        ("getenv", func(fn_str!(|k| { getenv(k) }))),
+        ("exit", func(exit)),
+        ("cwd", func(cwd)),
+        ("chdir", func(fn_str!(|p| { chdir(p) }))),
+        ("hostname", func(hostname)),
+        ("pid", func(|_| Ok(Int(std::process::id() as i64)))),
+        ("sh", func(sh)),
+        ("sh-stream", func(sh_stream)),
         ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
         ("list", func(|a| Ok(list!(a)))),
         ("list?", func(fn_is_type!(List(_, _)))),
         ("vector", func(|a| Ok(vector!(a)))),
+        ("vec", func(|a| Ok(vector!(seq_vec(&a[0], "vec")?)))),
         ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
+        ("hash-map", func(hash_map)),
         ("map?", func(fn_is_type!(Hash(_, _)))),
+        ("sorted-map", func(sorted_map)),
+        ("ordered-map", func(ordered_map)),
+        (
+            "sorted?",
+            func(fn_is_type!(Hash(ref hm, _) if hm.kind() == MapKind::Sorted)),
+        ),
         ("assoc", func(assoc)),
         ("dissoc", func(dissoc)),
         ("get", func(get)),
@@ -332,12 +1503,42 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("map", func(map)),
         ("conj", func(conj)),
         ("seq", func(seq)),
+        ("filter", func(filter)),
+        ("remove", func(remove)),
+        ("reduce", func(reduce)),
+        ("range", func(range)),
+        ("take", func(take)),
+        ("drop", func(drop)),
+        ("take-while", func(take_while)),
+        ("drop-while", func(drop_while)),
+        ("reverse", func(reverse)),
+        ("sort", func(sort)),
+        ("sort-by", func(sort_by)),
+        ("group-by", func(group_by)),
+        ("frequencies", func(frequencies)),
+        ("partition", func(partition)),
+        ("interleave", func(interleave)),
+        ("distinct", func(distinct)),
+        ("some", func(some)),
+        ("every?", func(every_q)),
+        ("last", func(last)),
+        ("butlast", func(butlast)),
+        ("compare", func(compare)),
+        (
+            "max-key",
+            func(|a| extreme_key(a, "max-key", Ordering::Greater)),
+        ),
+        (
+            "min-key",
+            func(|a| extreme_key(a, "min-key", Ordering::Less)),
+        ),
         ("meta", func(|a| a[0].get_meta())),
         ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
         ("atom", func(|a| Ok(atom(&a[0])))),
         ("atom?", func(fn_is_type!(Atom(_)))),
+        ("tagged-literal?", func(fn_is_type!(Tagged(_, _)))),
         ("deref", func(|a| a[0].deref())),
         ("reset!", func(|a| a[0].reset_bang(&a[1]))),
         ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
-    ]
+    ])
 }
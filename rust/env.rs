@@ -7,21 +7,102 @@ use crate::types::MalErr::ErrString;
 use crate::types::MalVal::{List, Nil, Sym, Vector};
 use crate::types::{error, MalErr, MalRet, MalVal};
 
+// meta holds the metadata given to bindings by def! and defmacro!
 #[derive(Debug)]
 pub struct EnvStruct {
     data: RefCell<FnvHashMap<String, MalVal>>,
+    meta: RefCell<FnvHashMap<String, MalVal>>,
     pub outer: Option<Env>,
 }
 
 pub type Env = Rc<EnvStruct>;
 
+// The dynamic vars of an interpreter: their root values, set by def!,
+// and the frames pushed by binding, innermost last
+#[derive(Debug, Default)]
+pub struct Dynamic {
+    roots: RefCell<FnvHashMap<String, MalVal>>,
+    frames: RefCell<Vec<FnvHashMap<String, MalVal>>>,
+}
+
+thread_local! {
+    // The dynamic vars of the interpreter running on this thread. They
+    // are reached through here rather than the global environment so
+    // that builtins, which have no environment, can read them with
+    // dyn_get.
+    static DYNAMIC: RefCell<Rc<Dynamic>> = RefCell::new(Rc::new(Dynamic::default()));
+}
+
+// Makes d the dynamic vars of this thread until the guard is dropped
+pub fn dyn_enter(d: &Rc<Dynamic>) -> DynGuard {
+    DynGuard(DYNAMIC.with(|cur| cur.replace(d.clone())))
+}
+
+pub struct DynGuard(Rc<Dynamic>);
+
+impl Drop for DynGuard {
+    fn drop(&mut self) {
+        DYNAMIC.with(|cur| cur.replace(self.0.clone()));
+    }
+}
+
+fn dynamic() -> Rc<Dynamic> {
+    DYNAMIC.with(|cur| cur.borrow().clone())
+}
+
+pub fn is_dynamic(key: &str) -> bool {
+    dynamic().roots.borrow().contains_key(key)
+}
+
+// Declares key as a dynamic var with the root value val
+pub fn dyn_def(key: &str, val: MalVal) {
+    dynamic().roots.borrow_mut().insert(key.to_string(), val);
+}
+
+pub fn dyn_get(key: &str) -> Option<MalVal> {
+    let d = dynamic();
+    let bound = d
+        .frames
+        .borrow()
+        .iter()
+        .rev()
+        .find_map(|f| f.get(key).cloned());
+    bound.or_else(|| d.roots.borrow().get(key).cloned())
+}
+
+// Sets the root value of key if it is a dynamic var, returning whether
+// it was. Bindings in effect still take precedence.
+fn dyn_set_root(key: &str, val: &MalVal) -> bool {
+    match dynamic().roots.borrow_mut().get_mut(key) {
+        Some(v) => {
+            *v = val.clone();
+            true
+        }
+        None => false,
+    }
+}
+
+// Calls f with the dynamic vars in binds bound to the values given, and
+// then pops the bindings, whether f succeeded or not
+pub fn dyn_binding<F: FnOnce() -> MalRet>(binds: Vec<(String, MalVal)>, f: F) -> MalRet {
+    if let Some((k, _)) = binds.iter().find(|(k, _)| !is_dynamic(k)) {
+        return error(&format!("can't dynamically bind non-dynamic var: {}", k));
+    }
+    let d = dynamic();
+    d.frames.borrow_mut().push(binds.into_iter().collect());
+    let res = f();
+    d.frames.borrow_mut().pop();
+    res
+}
+
 // TODO: it would be nice to use impl here but it doesn't work on
 // a deftype (i.e. Env)
 
 pub fn env_new(outer: Option<Env>) -> Env {
     Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
-        outer: outer,
+        meta: RefCell::new(FnvHashMap::default()),
+        outer,
     })
 }
 
@@ -47,10 +128,12 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Resul
     }
 }
 
+// Dynamic vars are found in the global (outermost) environment
 pub fn env_find(env: &Env, key: &str) -> Option<Env> {
     match (env.data.borrow().contains_key(key), env.outer.clone()) {
         (true, _) => Some(env.clone()),
         (false, Some(o)) => env_find(&o, key),
+        (false, None) if is_dynamic(key) => Some(env.clone()),
         _ => None,
     }
 }
@@ -62,8 +145,9 @@ pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
                 .data
                 .borrow()
                 .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
-                .clone()),
+                .cloned()
+                .or_else(|| dyn_get(s))
+                .ok_or(ErrString(format!("'{}' not found", s)))?),
             _ => error(&format!("'{}' not found", s)),
         },
         _ => error("Env.get called with non-Str"),
@@ -73,7 +157,7 @@ pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
         Sym(ref s) => {
-            env.data.borrow_mut().insert(s.to_string(), val.clone());
+            env_sets(env, s, val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
@@ -81,5 +165,33 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+    if env.outer.is_some() || !dyn_set_root(key, &val) {
+        env.data.borrow_mut().insert(key.to_string(), val);
+    }
+}
+
+// Sets the metadata of the binding of key in env; nil removes it
+pub fn env_set_meta(env: &Env, key: &str, meta: MalVal) {
+    match meta {
+        Nil => env.meta.borrow_mut().remove(key),
+        _ => env.meta.borrow_mut().insert(key.to_string(), meta),
+    };
+}
+
+// The metadata of the binding key is found by, or nil
+pub fn env_get_meta(env: &Env, key: &str) -> MalVal {
+    env_find(env, key)
+        .and_then(|e| e.meta.borrow().get(key).cloned())
+        .unwrap_or(Nil)
+}
+
+// Makes key a dynamic var with the value val. Only globals can be
+// dynamic, as other bindings are lexical.
+pub fn env_set_dynamic(env: &Env, key: &str, val: MalVal) -> MalRet {
+    if env.outer.is_some() {
+        return error(&format!("{} must be global to be ^:dynamic", key));
+    }
+    env.data.borrow_mut().remove(key);
+    dyn_def(key, val.clone());
+    Ok(val)
 }
@@ -0,0 +1,954 @@
+use std::rc::Rc;
+//use std::collections::HashMap;
+use fnv::FnvHashMap;
+use itertools::Itertools;
+
+use crate::env::{
+    dyn_binding, env_bind, env_find, env_get, env_new, env_set, env_set_dynamic, env_set_meta,
+    is_dynamic, Env,
+};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::{error, gensym, FnInfo, MalArgs, MalErr, MalMap, MalRet, MalVal, MapKind};
+
+fn quasiquote(ast: &MalVal) -> MalRet {
+    qq(ast, 1, &mut FnvHashMap::default())
+}
+
+fn is_form(v: &[MalVal], name: &str) -> bool {
+    v.len() == 2 && v[0] == Sym(name.to_string())
+}
+
+// Symbols ending in # are auto-gensyms: each becomes a new symbol, the
+// same one everywhere it appears in the quasiquote
+fn auto_gensym(ast: &MalVal, autos: &mut FnvHashMap<String, MalVal>) -> MalVal {
+    match ast {
+        Sym(s) if s.len() > 1 && s.ends_with('#') => autos
+            .entry(s.to_string())
+            .or_insert_with(|| gensym(&format!("{}__", &s[..s.len() - 1])))
+            .clone(),
+        _ => ast.clone(),
+    }
+}
+
+// Quasiquotes ast at the given depth of nested quasiquotes: only the
+// unquotes at depth 1 are evaluated, and deeper ones are kept, with the
+// forms under them quasiquoted at their own depth
+fn qq(ast: &MalVal, depth: usize, autos: &mut FnvHashMap<String, MalVal>) -> MalRet {
+    match ast {
+        List(v, _) if is_form(v, "unquote") || is_form(v, "splice-unquote") => {
+            match depth.checked_sub(1) {
+                Some(0) if is_form(v, "unquote") => Ok(v[1].clone()),
+                Some(d) if d > 0 => qq_form(&v[0], &v[1], d, autos),
+                _ => error("splice-unquote used outside a list or vector"),
+            }
+        }
+        List(v, _) if is_form(v, "quasiquote") => qq_form(&v[0], &v[1], depth + 1, autos),
+        List(v, _) => qq_seq(v, depth, autos),
+        Vector(v, _) => Ok(list![Sym("vec".to_string()), qq_seq(v, depth, autos)?]),
+        Hash(hm, _) => {
+            let ctor = match hm.kind() {
+                MapKind::Hash => "hash-map",
+                MapKind::Sorted => "sorted-map",
+                MapKind::Ordered => "ordered-map",
+            };
+            let mut l = vec![Sym(ctor.to_string())];
+            for (k, v) in hm.iter() {
+                l.push(Str(k.to_string()));
+                l.push(qq(v, depth, autos)?);
+            }
+            Ok(list!(l))
+        }
+        Sym(_) => Ok(list![Sym("quote".to_string()), auto_gensym(ast, autos)]),
+        _ => Ok(ast.clone()),
+    }
+}
+
+// A nested (unquote x), (splice-unquote x) or (quasiquote x), rebuilt
+// as (list 'name x) with x quasiquoted at depth
+fn qq_form(
+    name: &MalVal,
+    x: &MalVal,
+    depth: usize,
+    autos: &mut FnvHashMap<String, MalVal>,
+) -> MalRet {
+    Ok(list![
+        Sym("list".to_string()),
+        list![Sym("quote".to_string()), name.clone()],
+        qq(x, depth, autos)?
+    ])
+}
+
+// The elements of a list or vector, consed onto each other from the
+// right, with the splice-unquoted ones concatenated
+fn qq_seq(v: &[MalVal], depth: usize, autos: &mut FnvHashMap<String, MalVal>) -> MalRet {
+    let mut acc = list!(vec![]);
+    for elt in v.iter().rev() {
+        acc = match elt {
+            List(e, _) if depth == 1 && is_form(e, "splice-unquote") => {
+                list![Sym("concat".to_string()), e[1].clone(), acc]
+            }
+            _ => list![Sym("cons".to_string()), qq(elt, depth, autos)?, acc],
+        };
+    }
+    Ok(acc)
+}
+
+fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
+    match ast {
+        List(v, _) => match v.first() {
+            Some(Sym(ref s)) => match env_find(env, s) {
+                Some(e) => match env_get(&e, &v[0]) {
+                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
+    let mut was_expanded = false;
+    while let Some((mf, args)) = is_macro_call(&ast, env) {
+        //println!("macroexpand 1: {:?}", ast);
+        ast = match mf.apply(args) {
+            Err(e) => return (false, Err(e)),
+            Ok(a) => a,
+        };
+        //println!("macroexpand 2: {:?}", ast);
+        was_expanded = true;
+    }
+    (was_expanded, Ok(ast))
+}
+
+// The name given to def! or defmacro!, and the metadata given with it,
+// as in (def! ^{:doc "..."} name val), which reads as
+// (def! (with-meta name {:doc "..."}) val)
+fn def_name(name: &MalVal, env: &Env) -> Result<(MalVal, MalVal), MalErr> {
+    match name {
+        List(l, _) if l.len() == 3 && l[0] == Sym("with-meta".to_string()) => {
+            match (&l[1], eval(l[2].clone(), env.clone())?) {
+                (Sym(_), meta @ Hash(..)) | (Sym(_), meta @ Nil) => Ok((l[1].clone(), meta)),
+                (Sym(_), meta) => Err(ErrString(format!(
+                    "metadata must be a map, got {}",
+                    meta.pr_str(true)
+                ))),
+                _ => Err(ErrString("def! with non-Sym name".to_string())),
+            }
+        }
+        _ => Ok((name.clone(), Nil)),
+    }
+}
+
+// Sets the metadata of the var name to meta, adding :arglists for a
+// function when it is not given, and keeping :dynamic on a dynamic var
+fn set_var_meta(env: &Env, name: &MalVal, meta: MalVal, val: &MalVal) {
+    let name = match name {
+        Sym(s) => s,
+        _ => return,
+    };
+    // a dynamic var redefined without ^:dynamic stays dynamic
+    let dynamic = env.outer.is_none() && is_dynamic(name);
+    let params = match val {
+        MalFunc { params, .. } => Some(params),
+        _ if !dynamic => return env_set_meta(env, name, meta),
+        _ => None,
+    };
+    let mut hm = match meta {
+        Hash(hm, _) => (*hm).clone(),
+        _ => MalMap::new(MapKind::Hash),
+    };
+    if let Some(params) = params.filter(|_| !hm.contains_key("\u{29e}arglists")) {
+        let params = match &**params {
+            List(p, _) | Vector(p, _) => vector!(p.to_vec()),
+            p => p.clone(),
+        };
+        hm.insert("\u{29e}arglists".to_string(), list!(vec![params]));
+    }
+    if dynamic {
+        hm.insert("\u{29e}dynamic".to_string(), Bool(true));
+    }
+    let meta = Hash(Rc::new(hm), Rc::new(Nil));
+    env_set_meta(env, name, meta);
+}
+
+// loop* and fn* forms that have had their recur checked are marked with
+// this key in their metadata, set to whether the body recurs, so that
+// the check is done once rather than every time they are evaluated
+const RECUR_KEY: &str = "\u{29e}recur";
+
+fn recur_checked(form: &MalVal) -> Option<bool> {
+    match form {
+        List(_, meta) => match &**meta {
+            Hash(hm, _) => match hm.get(RECUR_KEY) {
+                Some(Bool(b)) => Some(*b),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn mark_recur(l: Vec<MalVal>, meta: &MalVal, recurs: bool) -> MalVal {
+    let mut hm = match meta {
+        Hash(hm, _) => (**hm).clone(),
+        _ => MalMap::new(MapKind::Hash),
+    };
+    hm.insert(RECUR_KEY.to_string(), Bool(recurs));
+    List(Rc::new(l), Rc::new(Hash(Rc::new(hm), Rc::new(Nil))))
+}
+
+// The names bound by fn* params or loop* bindings
+fn bound_names(binds: &[MalVal], step: usize) -> Vec<String> {
+    binds
+        .iter()
+        .step_by(step)
+        .filter_map(|b| match b {
+            Sym(s) if s != "&" => Some(s.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_clause(form: &MalVal, name: &str) -> bool {
+    match form {
+        List(c, _) => c.first() == Some(&Sym(name.to_string())),
+        _ => false,
+    }
+}
+
+// The index of the name a catch* clause binds: (catch* e body...) catches
+// everything, while (catch* :kind e body...) and (catch* (pred) e body...)
+// only catch what matches. A test is a keyword or a list, such as
+// #(string? %), so a symbol right after catch* is always the name.
+fn catch_binding(c: &[MalVal]) -> Result<usize, MalErr> {
+    match (c.get(1), c.get(2)) {
+        (Some(Sym(_)), _) => Ok(1),
+        (Some(k @ Str(_)), Some(Sym(_))) if k.keyword_q() => Ok(2),
+        (Some(List(..)), Some(Sym(_))) => Ok(2),
+        _ => Err(ErrString(
+            "catch* needs a symbol to bind the exception to, after an optional keyword or list test"
+                .to_string(),
+        )),
+    }
+}
+
+// A try* form split into its body, catch* clauses and finally clause,
+// checking that the clauses come last and finally comes after catch*
+type TryParts<'a> = (Vec<MalVal>, Vec<&'a MalVal>, Option<&'a MalVal>);
+
+fn try_parts(l: &[MalVal]) -> Result<TryParts<'_>, MalErr> {
+    let start = l
+        .iter()
+        .position(|f| is_clause(f, "catch*") || is_clause(f, "finally"))
+        .unwrap_or(l.len());
+    let (mut catches, mut finally) = (vec![], None);
+    for f in &l[start..] {
+        if finally.is_some() {
+            return Err(ErrString(
+                "finally must be the last clause of try*".to_string(),
+            ));
+        }
+        match f {
+            _ if is_clause(f, "finally") => finally = Some(f),
+            List(c, _) if is_clause(f, "catch*") => {
+                catch_binding(c)?;
+                catches.push(f);
+            }
+            _ => {
+                return Err(ErrString(format!(
+                    "try* body must come before its catch* and finally clauses, got {}",
+                    f.pr_str(true)
+                )))
+            }
+        }
+    }
+    Ok((l[1..start.max(1)].to_vec(), catches, finally))
+}
+
+// Whether the catch* test matches the error: :default matches anything,
+// :error the interpreter's own errors, another keyword a thrown map with
+// that :type, and a list is evaluated to a predicate of the
+// exception
+fn catch_matches(
+    test: Option<&MalVal>,
+    e: &MalErr,
+    exc: &MalVal,
+    env: &Env,
+) -> Result<bool, MalErr> {
+    match test {
+        None => Ok(true),
+        Some(Str(k)) if k == "\u{29e}default" => Ok(true),
+        Some(Str(k)) if k == "\u{29e}error" => Ok(matches!(e, ErrString(_))),
+        Some(kind @ Str(_)) if kind.keyword_q() => Ok(match exc {
+            Hash(hm, _) => hm.get("\u{29e}type") == Some(kind),
+            _ => false,
+        }),
+        Some(pred) => match eval(pred.clone(), env.clone())?.apply(vec![exc.clone()])? {
+            Bool(false) | Nil => Ok(false),
+            _ => Ok(true),
+        },
+    }
+}
+
+// Evaluates the body of a try*, handing an error to the first catch*
+// clause that matches it, or passing it on when none does
+fn try_catch(body: Vec<MalVal>, catches: &[&MalVal], env: &Env) -> MalRet {
+    let body = match body.len() {
+        1 => body[0].clone(),
+        _ => list!([vec![Sym("do".to_string())], body].concat()),
+    };
+    let e = match eval(body, env.clone()) {
+        Err(e) => e,
+        res => return res,
+    };
+    let exc = match &e {
+        ErrMalVal(mv) => mv.clone(),
+        ErrString(s) => Str(s.to_string()),
+    };
+    for c in catches {
+        let c = match c {
+            List(c, _) => c,
+            _ => continue,
+        };
+        let i = catch_binding(c)?;
+        let test = if i == 2 { Some(&c[1]) } else { None };
+        if catch_matches(test, &e, &exc, env)? {
+            let catch_env = env_bind(Some(env.clone()), list!(vec![c[i].clone()]), vec![exc])?;
+            let handler = list!([vec![Sym("do".to_string())], c[i + 1..].to_vec()].concat());
+            return eval(handler, catch_env);
+        }
+    }
+    Err(e)
+}
+
+// Expands the macros in a form and all its subforms, the way eval would
+// see them: quoted forms are left alone, the names bound by fn*, let*,
+// loop* and catch* aren't expanded, and neither are calls to macros that
+// those names shadow.
+//
+// When check is set, it also checks that recur is only used in tail
+// position of a loop* or fn* body, with one argument per binding or
+// parameter, and marks the loop* and fn* forms it has checked so that
+// eval doesn't walk them again.
+struct Expander<'a> {
+    env: &'a Env,
+    locals: Vec<String>,
+    check: bool,
+    arity: usize,
+}
+
+impl<'a> Expander<'a> {
+    fn new(env: &'a Env, check: bool) -> Expander<'a> {
+        Expander {
+            env,
+            locals: vec![],
+            check,
+            arity: 0,
+        }
+    }
+
+    // The walker for a nested loop* or fn* body, with names as locals
+    fn inner(&self, names: Vec<String>) -> Expander<'a> {
+        Expander {
+            env: self.env,
+            locals: [self.locals.clone(), names].concat(),
+            check: self.check,
+            arity: 0,
+        }
+    }
+
+    // Returns form expanded, and whether it recurs
+    fn expand(&mut self, form: &MalVal, tail: bool) -> Result<(MalVal, bool), MalErr> {
+        match form {
+            List(l, meta) if !l.is_empty() => self.expand_list(form, l, meta, tail),
+            Vector(l, meta) => {
+                let (l, _) = self.expand_all(l, false)?;
+                Ok((Vector(Rc::new(l), meta.clone()), false))
+            }
+            Hash(hm, meta) => {
+                let mut new_hm = MalMap::new(hm.kind());
+                for (k, v) in hm.iter() {
+                    new_hm.insert(k.to_string(), self.expand(v, false)?.0);
+                }
+                Ok((Hash(Rc::new(new_hm), meta.clone()), false))
+            }
+            _ => Ok((form.clone(), false)),
+        }
+    }
+
+    fn expand_all(&mut self, forms: &[MalVal], tail: bool) -> Result<(Vec<MalVal>, bool), MalErr> {
+        let mut recurs = false;
+        let mut checked = vec![];
+        for (i, f) in forms.iter().enumerate() {
+            let (f, r) = self.expand(f, tail && i == forms.len() - 1)?;
+            recurs |= r;
+            checked.push(f);
+        }
+        Ok((checked, recurs))
+    }
+
+    // Expands the forms from start on, the last one in tail position if
+    // tail, keeping the ones before start as they are
+    fn expand_from(
+        &mut self,
+        l: &[MalVal],
+        meta: &Rc<MalVal>,
+        start: usize,
+        tail: bool,
+    ) -> Result<(MalVal, bool), MalErr> {
+        let (rest, recurs) = self.expand_all(&l[start.min(l.len())..], tail)?;
+        let l = [l[..start.min(l.len())].to_vec(), rest].concat();
+        Ok((List(Rc::new(l), meta.clone()), recurs))
+    }
+
+    // Expands let* or loop* style bindings, leaving their names as locals
+    fn expand_binds(&mut self, binds: &MalVal) -> Result<MalVal, MalErr> {
+        match binds {
+            List(b, meta) | Vector(b, meta) => {
+                let mut checked = vec![];
+                for pair in b.chunks(2) {
+                    checked.push(pair[0].clone());
+                    if let Some(e) = pair.get(1) {
+                        checked.push(self.expand(e, false)?.0);
+                    }
+                    if let Sym(s) = &pair[0] {
+                        self.locals.push(s.to_string());
+                    }
+                }
+                Ok(match binds {
+                    List(..) => List(Rc::new(checked), meta.clone()),
+                    _ => Vector(Rc::new(checked), meta.clone()),
+                })
+            }
+            _ => Ok(binds.clone()),
+        }
+    }
+
+    fn expand_list(
+        &mut self,
+        form: &MalVal,
+        l: &[MalVal],
+        meta: &Rc<MalVal>,
+        tail: bool,
+    ) -> Result<(MalVal, bool), MalErr> {
+        let special = match &l[0] {
+            Sym(s) => s.as_str(),
+            _ => "",
+        };
+        match special {
+            "quote" | "quasiquoteexpand" | "macroexpand" | "macroexpand-1" | "macroexpand-all" => {
+                Ok((form.clone(), false))
+            }
+            "quasiquote" if l.len() > 1 => {
+                let x = self.expand_unquoted(&l[1], 1)?;
+                Ok((List(Rc::new(vec![l[0].clone(), x]), meta.clone()), false))
+            }
+            "fn*" => Ok((self.expand_fn(form)?, false)),
+            "loop*" => Ok((self.expand_loop(form)?, false)),
+            "recur" if self.check => {
+                if !tail {
+                    return Err(ErrString(
+                        "recur must be in tail position of a loop* or fn*".to_string(),
+                    ));
+                }
+                if l.len() - 1 != self.arity {
+                    return Err(ErrString(format!(
+                        "recur: expected {} args, got {}",
+                        self.arity,
+                        l.len() - 1
+                    )));
+                }
+                let (checked, _) = self.expand_from(l, meta, 1, false)?;
+                Ok((checked, true))
+            }
+            "if" => {
+                let mut checked = vec![l[0].clone()];
+                let mut recurs = false;
+                for (i, f) in l.iter().enumerate().skip(1) {
+                    let (f, r) = self.expand(f, tail && i > 1)?;
+                    recurs |= r;
+                    checked.push(f);
+                }
+                Ok((List(Rc::new(checked), meta.clone()), recurs))
+            }
+            "do" => self.expand_from(l, meta, 1, tail),
+            "let*" if l.len() > 1 => {
+                let n = self.locals.len();
+                let binds = self.expand_binds(&l[1])?;
+                let res = self.expand_from(
+                    &[vec![l[0].clone(), binds], l[2..].to_vec()].concat(),
+                    meta,
+                    2,
+                    tail,
+                );
+                self.locals.truncate(n);
+                res
+            }
+            "def!" | "defmacro!" => self.expand_from(l, meta, 2, false),
+            "binding" if l.len() > 1 => {
+                let binds = match &l[1] {
+                    Vector(b, m) => Vector(Rc::new(self.expand_all(b, false)?.0), m.clone()),
+                    b => b.clone(),
+                };
+                self.expand_from(
+                    &[vec![l[0].clone(), binds], l[2..].to_vec()].concat(),
+                    meta,
+                    2,
+                    false,
+                )
+            }
+            "try*" => {
+                let mut checked = vec![l[0].clone()];
+                for f in &l[1..] {
+                    checked.push(match f {
+                        List(c, cmeta) if is_clause(f, "catch*") => {
+                            let i = catch_binding(c)?;
+                            let test = match i {
+                                2 => vec![self.expand(&c[1], false)?.0],
+                                _ => vec![],
+                            };
+                            let n = self.locals.len();
+                            if let Sym(s) = &c[i] {
+                                self.locals.push(s.to_string());
+                            }
+                            let c = [vec![c[0].clone()], test, c[i..].to_vec()].concat();
+                            let res = self.expand_from(&c, cmeta, i + 1, false);
+                            self.locals.truncate(n);
+                            res?.0
+                        }
+                        List(c, cmeta) if is_clause(f, "finally") => {
+                            self.expand_from(c, cmeta, 1, false)?.0
+                        }
+                        _ => self.expand(f, false)?.0,
+                    });
+                }
+                Ok((List(Rc::new(checked), meta.clone()), false))
+            }
+            "eval" => self.expand_from(l, meta, 1, false),
+            s if !self.locals.iter().any(|n| n == s) => match is_macro_call(form, self.env) {
+                Some((mf, args)) => self.expand(&mf.apply(args)?, tail),
+                None => self.expand_from(l, meta, 0, false),
+            },
+            _ => self.expand_from(l, meta, 0, false),
+        }
+    }
+
+    // Expands a fn* form, checking its body against its own params
+    fn expand_fn(&self, form: &MalVal) -> Result<MalVal, MalErr> {
+        let (l, meta) = match form {
+            List(l, meta) if l.len() > 2 && recur_checked(form).is_none() => (l, meta),
+            _ => return Ok(form.clone()),
+        };
+        let names = match &l[1] {
+            List(p, _) | Vector(p, _) => bound_names(p, 1),
+            _ => vec![],
+        };
+        let mut inner = self.inner(names);
+        inner.arity = inner.locals.len() - self.locals.len();
+        let (body, recurs) = inner.expand_all(&l[2..], true)?;
+        let l = [l[..2].to_vec(), body].concat();
+        Ok(match self.check {
+            true => mark_recur(l, meta, recurs),
+            false => List(Rc::new(l), meta.clone()),
+        })
+    }
+
+    // Expands a loop* form, its bindings with the current locals and its
+    // body checked against its own bindings
+    fn expand_loop(&self, form: &MalVal) -> Result<MalVal, MalErr> {
+        let (l, meta) = match form {
+            List(l, meta) if l.len() > 1 && recur_checked(form).is_none() => (l, meta),
+            _ => return Ok(form.clone()),
+        };
+        let mut inner = self.inner(vec![]);
+        let binds = inner.expand_binds(&l[1])?;
+        inner.arity = match &l[1] {
+            List(b, _) | Vector(b, _) => bound_names(b, 2).len(),
+            _ => 0,
+        };
+        let (body, _) = inner.expand_all(&l[2..], true)?;
+        let l = [vec![l[0].clone(), binds], body].concat();
+        Ok(match self.check {
+            true => mark_recur(l, meta, false),
+            false => List(Rc::new(l), meta.clone()),
+        })
+    }
+
+    // Expands the parts of a quasiquoted form that are unquoted at depth 1
+    fn expand_unquoted(&mut self, form: &MalVal, depth: usize) -> MalRet {
+        match form {
+            List(l, meta) if is_form(l, "unquote") || is_form(l, "splice-unquote") => {
+                let x = match depth {
+                    1 => self.expand(&l[1], false)?.0,
+                    _ => self.expand_unquoted(&l[1], depth - 1)?,
+                };
+                Ok(List(Rc::new(vec![l[0].clone(), x]), meta.clone()))
+            }
+            List(l, meta) if is_form(l, "quasiquote") => {
+                let x = self.expand_unquoted(&l[1], depth + 1)?;
+                Ok(List(Rc::new(vec![l[0].clone(), x]), meta.clone()))
+            }
+            List(l, meta) | Vector(l, meta) => {
+                let mut expanded = vec![];
+                for f in l.iter() {
+                    expanded.push(self.expand_unquoted(f, depth)?);
+                }
+                Ok(match form {
+                    List(..) => List(Rc::new(expanded), meta.clone()),
+                    _ => Vector(Rc::new(expanded), meta.clone()),
+                })
+            }
+            Hash(hm, meta) => {
+                let mut new_hm = MalMap::new(hm.kind());
+                for (k, v) in hm.iter() {
+                    new_hm.insert(k.to_string(), self.expand_unquoted(v, depth)?);
+                }
+                Ok(Hash(Rc::new(new_hm), meta.clone()))
+            }
+            _ => Ok(form.clone()),
+        }
+    }
+}
+
+fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
+    match ast {
+        Sym(_) => Ok(env_get(env, ast)?),
+        List(v, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in v.iter() {
+                lst.push(eval(a.clone(), env.clone())?)
+            }
+            Ok(list!(lst))
+        }
+        Vector(v, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in v.iter() {
+                lst.push(eval(a.clone(), env.clone())?)
+            }
+            Ok(vector!(lst))
+        }
+        Hash(hm, _) => {
+            let mut new_hm = MalMap::new(hm.kind());
+            for (k, v) in hm.iter() {
+                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+            }
+            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+        }
+        _ => Ok(ast.clone()),
+    }
+}
+
+// What recur rebinds: the loop* names, body and the env the loop* was
+// evaluated in
+struct RecurTarget {
+    names: Vec<MalVal>,
+    body: MalVal,
+    env: Env,
+}
+
+pub fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+    let ret: MalRet;
+    // set by loop* and only kept through tail positions, so a recur
+    // anywhere else finds none
+    let mut target: Option<Rc<RecurTarget>> = None;
+
+    'tco: loop {
+        ret = match ast.clone() {
+            List(l, _) => {
+                if l.is_empty() {
+                    return Ok(ast);
+                }
+                let a0 = &l[0];
+                match a0 {
+                    Sym(ref a0sym) if a0sym == "def!" => {
+                        let (name, meta) = def_name(&l[1], &env)?;
+                        let dynamic = match &meta {
+                            Hash(hm, _) => hm.get("\u{29e}dynamic") == Some(&Bool(true)),
+                            _ => false,
+                        };
+                        let val = eval(l[2].clone(), env.clone())?.named(&name, &ast);
+                        set_var_meta(&env, &name, meta, &val);
+                        match name {
+                            Sym(ref s) if dynamic => env_set_dynamic(&env, s, val),
+                            _ => env_set(&env, name, val),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "binding" => {
+                        let binds = match l.get(1) {
+                            Some(List(b, _)) | Some(Vector(b, _)) if b.len() % 2 == 0 => b.clone(),
+                            _ => return error("binding: expecting a vector of bindings"),
+                        };
+                        let mut vals = vec![];
+                        for (b, e) in binds.iter().tuples() {
+                            match b {
+                                Sym(s) => vals.push((s.to_string(), eval(e.clone(), env.clone())?)),
+                                _ => return error("binding with non-Sym binding"),
+                            }
+                        }
+                        let body = list!([vec![Sym("do".to_string())], l[2..].to_vec()].concat());
+                        dyn_binding(vals, || eval(body, env.clone()))
+                    }
+                    Sym(ref a0sym) if a0sym == "let*" => {
+                        env = env_new(Some(env.clone()));
+                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        match a1 {
+                            List(ref binds, _) | Vector(ref binds, _) => {
+                                for (b, e) in binds.iter().tuples() {
+                                    match b {
+                                        Sym(_) => {
+                                            let _ = env_set(
+                                                &env,
+                                                b.clone(),
+                                                eval(e.clone(), env.clone())?,
+                                            );
+                                        }
+                                        _ => {
+                                            return error("let* with non-Sym binding");
+                                        }
+                                    }
+                                }
+                            }
+                            _ => {
+                                return error("let* with non-List bindings");
+                            }
+                        };
+                        ast = a2;
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
+                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => quasiquote(&l[1]),
+                    Sym(ref a0sym) if a0sym == "quasiquote" => {
+                        ast = quasiquote(&l[1])?;
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "defmacro!" => {
+                        let (a1, var_meta) = def_name(&l[1], &env)?;
+                        let r = eval(l[2].clone(), env.clone())?;
+                        match r {
+                            MalFunc {
+                                eval,
+                                ast: mast,
+                                env,
+                                params,
+                                info,
+                                ..
+                            } => {
+                                let mac = MalFunc {
+                                    eval,
+                                    ast: mast.clone(),
+                                    env: env.clone(),
+                                    params: params.clone(),
+                                    is_macro: true,
+                                    meta: Rc::new(Nil),
+                                    info,
+                                }
+                                .named(&a1, &ast);
+                                set_var_meta(&env, &a1, var_meta, &mac);
+                                Ok(env_set(&env, a1.clone(), mac)?)
+                            }
+                            _ => error("set_macro on non-function"),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "macroexpand" => {
+                        match macroexpand(l[1].clone(), &env) {
+                            (_, Ok(new_ast)) => Ok(new_ast),
+                            (_, e) => return e,
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "macroexpand-1" => {
+                        match is_macro_call(&l[1], &env) {
+                            Some((mf, args)) => mf.apply(args),
+                            None => Ok(l[1].clone()),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "macroexpand-all" => {
+                        Ok(Expander::new(&env, false).expand(&l[1], false)?.0)
+                    }
+                    Sym(ref a0sym) if a0sym == "try*" => {
+                        let (body, catches, finally) = try_parts(&l)?;
+                        let res = try_catch(body, &catches, &env);
+                        if let Some(List(f, _)) = finally {
+                            let f = list!([vec![Sym("do".to_string())], f[1..].to_vec()].concat());
+                            eval(f, env.clone())?;
+                        }
+                        res
+                    }
+                    Sym(ref a0sym) if a0sym == "do" && l.len() == 1 => Ok(Nil),
+                    Sym(ref a0sym) if a0sym == "do" => {
+                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
+                            List(_, _) => {
+                                ast = l.last().unwrap_or(&Nil).clone();
+                                continue 'tco;
+                            }
+                            _ => error("invalid do form"),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "if" => {
+                        let cond = eval(l[1].clone(), env.clone())?;
+                        match cond {
+                            Bool(false) | Nil if l.len() >= 4 => {
+                                ast = l[3].clone();
+                                continue 'tco;
+                            }
+                            Bool(false) | Nil => Ok(Nil),
+                            _ if l.len() >= 3 => {
+                                ast = l[2].clone();
+                                continue 'tco;
+                            }
+                            _ => Ok(Nil),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "fn*" => {
+                        // the body is macroexpanded once, when the fn* is
+                        // created, with the macros defined then: redefining
+                        // a macro only changes functions created afterwards.
+                        // Calls to macros not yet defined are expanded when
+                        // they are evaluated.
+                        let checked = match recur_checked(&ast) {
+                            Some(_) => ast.clone(),
+                            None => Expander::new(&env, true).expand_fn(&ast)?,
+                        };
+                        let (a1, mut a2) = match &checked {
+                            List(c, _) if c.len() > 1 => {
+                                (c[1].clone(), c.get(2).cloned().unwrap_or(Nil))
+                            }
+                            _ => return error("fn*: expecting a parameter list"),
+                        };
+                        if recur_checked(&checked) == Some(true) {
+                            // recur in a fn* body is recur in a loop* over its params
+                            let mut binds = vec![];
+                            if let List(p, _) | Vector(p, _) = &a1 {
+                                for n in bound_names(p, 1) {
+                                    binds.push(Sym(n.clone()));
+                                    binds.push(Sym(n));
+                                }
+                            }
+                            a2 = mark_recur(
+                                vec![Sym("loop*".to_string()), vector!(binds), a2],
+                                &Nil,
+                                false,
+                            );
+                        }
+                        Ok(MalFunc {
+                            eval,
+                            ast: Rc::new(a2),
+                            env,
+                            params: Rc::new(a1),
+                            is_macro: false,
+                            meta: Rc::new(Nil),
+                            info: Rc::new(FnInfo::new(&ast)),
+                        })
+                    }
+                    Sym(ref a0sym) if a0sym == "loop*" => {
+                        let checked = match recur_checked(&ast) {
+                            Some(_) => ast.clone(),
+                            None => Expander::new(&env, true).expand_loop(&ast)?,
+                        };
+                        let c = match &checked {
+                            List(c, _) => c.clone(),
+                            _ => l.clone(),
+                        };
+                        let binds = match c.get(1) {
+                            Some(List(b, _)) | Some(Vector(b, _)) if b.len() % 2 == 0 => b.clone(),
+                            _ => return error("loop*: expecting a vector of bindings"),
+                        };
+                        let body = match c.len() {
+                            3 => c[2].clone(),
+                            _ => list!([vec![Sym("do".to_string())], c[2..].to_vec()].concat()),
+                        };
+                        let outer = env.clone();
+                        env = env_new(Some(outer.clone()));
+                        let mut names = vec![];
+                        for (b, e) in binds.iter().tuples() {
+                            match b {
+                                Sym(_) => {
+                                    env_set(&env, b.clone(), eval(e.clone(), env.clone())?)?;
+                                    names.push(b.clone());
+                                }
+                                _ => return error("loop* with non-Sym binding"),
+                            }
+                        }
+                        target = Some(Rc::new(RecurTarget {
+                            names,
+                            body: body.clone(),
+                            env: outer,
+                        }));
+                        ast = body;
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "recur" => {
+                        let t = match &target {
+                            Some(t) => t.clone(),
+                            None => {
+                                return error("recur must be in tail position of a loop* or fn*")
+                            }
+                        };
+                        if l.len() - 1 != t.names.len() {
+                            return error(&format!(
+                                "recur: expected {} args, got {}",
+                                t.names.len(),
+                                l.len() - 1
+                            ));
+                        }
+                        let mut vals = vec![];
+                        for a in l[1..].iter() {
+                            vals.push(eval(a.clone(), env.clone())?);
+                        }
+                        env = env_new(Some(t.env.clone()));
+                        for (n, v) in t.names.iter().zip(vals) {
+                            env_set(&env, n.clone(), v)?;
+                        }
+                        ast = t.body.clone();
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "eval" => {
+                        ast = eval(l[1].clone(), env.clone())?;
+                        target = None;
+                        while let Some(ref e) = env.clone().outer {
+                            env = e.clone();
+                        }
+                        continue 'tco;
+                    }
+                    _ => {
+                        // the head is looked up once, both to call it and
+                        // to find out whether the call is a macro call
+                        let f = eval(a0.clone(), env.clone())?;
+                        if let (Sym(_), MalFunc { is_macro: true, .. }) = (a0, &f) {
+                            ast = f.apply(l[1..].to_vec())?;
+                            continue 'tco;
+                        }
+                        let mut args = vec![];
+                        for a in l[1..].iter() {
+                            args.push(eval(a.clone(), env.clone())?);
+                        }
+                        match f {
+                            Func(_, _) => f.apply(args),
+                            MalFunc {
+                                ast: mast,
+                                env: menv,
+                                params,
+                                ..
+                            } => {
+                                env = env_bind(Some(menv.clone()), (*params).clone(), args)?;
+                                ast = (*mast).clone();
+                                target = None;
+                                continue 'tco;
+                            }
+                            _ => error("attempt to call non-function"),
+                        }
+                    }
+                }
+            }
+            _ => eval_ast(&ast, &env),
+        };
+
+        break;
+    } // end 'tco loop
+
+    ret
+}
@@ -0,0 +1,212 @@
+use std::rc::Rc;
+
+use crate::core;
+use crate::env::{dyn_enter, dyn_get, env_get, env_get_meta, env_new, env_sets, Dynamic, Env};
+use crate::eval::eval;
+use crate::reader::{forms, read_str, ReadLimits};
+use crate::types::MalVal::{Bool, Hash, Int, MalFunc, Nil, Str, Sym};
+use crate::types::{error, named_func, MalArgs, MalErr, MalRet, MalVal};
+
+// core.mal: defined using the language itself
+const PRELUDE: &[&str] = &[
+    "(def! not (fn* (a) (if a false true)))",
+    "(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
+    "(def! ^:dynamic *print-pretty* true)",
+    "(def! ^:dynamic *print-right-margin* 80)",
+    "(def! pprint (fn* (x & w) (println (pprint-str x (if (empty? w) *print-right-margin* (first w))))))",
+    "(defmacro! doc (fn* (name) (list 'println (list 'doc-string (list 'quote name)))))",
+    "(defmacro! with-out-str (fn* (& body) (list 'with-out-str* (list 'fn* '() (cons 'do body)))))",
+];
+
+// (load-file path): evaluates the forms in the file one at a time, with
+// *file* set to path. An error says which form it was in.
+fn load_file(env: &Env, a: MalArgs) -> MalRet {
+    let path = match a.first() {
+        Some(Str(p)) => p.to_string(),
+        _ => return error("load-file: expecting a file name"),
+    };
+    let src = core::read_file("load-file", &path)?;
+    let file = dyn_get("*file*").unwrap_or(Nil);
+    env_sets(env, "*file*", Str(path.clone()));
+    let res = eval_forms(env, &src, &path);
+    env_sets(env, "*file*", file);
+    res.map(|_| Nil)
+}
+
+fn eval_forms(env: &Env, src: &str, path: &str) -> Result<(), MalErr> {
+    let mut forms = forms(src, Some(path), ReadLimits::current()?)?;
+    while let Some(form) = forms.next() {
+        form.and_then(|f| eval(f, env.clone()))
+            .map_err(|e| forms.locate(e))?;
+    }
+    Ok(())
+}
+
+// (meta-of-var 'name): the metadata def! or defmacro! gave name
+fn meta_of_var(env: &Env, a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(name @ Sym(s)) => {
+            env_get(env, name)?;
+            Ok(env_get_meta(env, s))
+        }
+        _ => error("meta-of-var: expecting a symbol"),
+    }
+}
+
+// (doc-string 'name): the documentation doc prints, Clojure style
+fn doc_string(env: &Env, a: MalArgs) -> MalRet {
+    let val = match a.first() {
+        Some(name @ Sym(_)) => env_get(env, name)?,
+        _ => return error("doc-string: expecting a symbol"),
+    };
+    let meta = meta_of_var(env, a.clone())?;
+    let get = |k: &str| match &meta {
+        Hash(hm, _) => hm.get(&format!("\u{29e}{}", k)).cloned(),
+        _ => None,
+    };
+    let mut lines = vec!["-------------------------".to_string(), a[0].pr_str(true)];
+    if let Some(arglists) = get("arglists") {
+        lines.push(arglists.pr_str(true));
+    }
+    if let MalFunc { is_macro: true, .. } = val {
+        lines.push("Macro".to_string());
+    }
+    if let Some(Str(doc)) = get("doc") {
+        lines.push(format!("  {}", doc));
+    }
+    Ok(Str(lines.join("\n")))
+}
+
+type EnvFn = fn(&Env, MalArgs) -> MalRet;
+
+// Natives that need the global environment, which they are given
+const ENV_NATIVES: &[(&str, EnvFn)] = &[
+    ("load-file", load_file),
+    ("meta-of-var", meta_of_var),
+    ("doc-string", doc_string),
+];
+
+/// A mal interpreter: a global environment with `core::ns` and the
+/// mal-defined prelude loaded.
+///
+/// ```
+/// use mal::{Interpreter, MalVal};
+/// use std::cell::Cell;
+/// use std::convert::TryFrom;
+/// use std::rc::Rc;
+///
+/// let interp = Interpreter::new();
+/// let calls = Rc::new(Cell::new(0));
+/// let counter = calls.clone();
+/// interp.register("tick!", move |_| {
+///     counter.set(counter.get() + 1);
+///     Ok(MalVal::Nil)
+/// });
+/// interp.register("double", |a| match a[0] {
+///     MalVal::Int(i) => Ok(MalVal::Int(i * 2)),
+///     _ => mal::types::error("double: not an Int"),
+/// });
+/// interp.set_global("x", 20);
+/// let r = interp.eval_str("(do (tick!) (tick!) (+ (double x) 2))").unwrap();
+/// assert_eq!(i64::try_from(r).unwrap(), 42);
+/// assert_eq!(calls.get(), 2);
+/// ```
+///
+/// Each interpreter has its own dynamic vars, such as `*print-length*`
+/// and `*out*`, which are made the thread's current ones while one of
+/// its methods runs:
+///
+/// ```
+/// use mal::{Interpreter, MalVal};
+///
+/// let a = Interpreter::new();
+/// a.eval_str("(def! *print-length* 1)").unwrap();
+/// let b = Interpreter::new();
+/// let printed = |i: &Interpreter| i.eval_str("(pr-str [1 2])").unwrap();
+/// assert_eq!(printed(&a), MalVal::Str("[1 ...]".to_string()));
+/// assert_eq!(printed(&b), MalVal::Str("[1 2]".to_string()));
+/// ```
+pub struct Interpreter {
+    env: Env,
+    dynamic: Rc<Dynamic>,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        let dynamic = Rc::new(Dynamic::default());
+        let _d = dyn_enter(&dynamic);
+        let env = env_new(None);
+        for (k, v) in core::ns() {
+            env_sets(&env, k, v);
+        }
+        env_sets(&env, "*ARGV*", list![]);
+        for &(name, f) in ENV_NATIVES {
+            let root = env.clone();
+            env_sets(&env, name, named_func(name, move |a| f(&root, a)));
+        }
+        let interp = Interpreter { env, dynamic };
+        for src in PRELUDE {
+            if let Err(e) = interp.eval_str(src) {
+                panic!("mal prelude failed: {:?}", e);
+            }
+        }
+        interp
+    }
+
+    /// The global environment
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub fn eval(&self, ast: MalVal) -> MalRet {
+        let _d = dyn_enter(&self.dynamic);
+        eval(ast, self.env.clone())
+    }
+
+    /// Reads one form from `src` and evaluates it
+    pub fn eval_str(&self, src: &str) -> MalRet {
+        let _d = dyn_enter(&self.dynamic);
+        self.eval(read_str(src.to_string())?)
+    }
+
+    pub fn load_file(&self, path: &str) -> MalRet {
+        self.eval(list![Sym("load-file".to_string()), Str(path.to_string())])
+    }
+
+    /// Defines `name` as a native function in the global environment.
+    /// `f` may capture state, e.g. a database handle.
+    pub fn register<F: Fn(MalArgs) -> MalRet + 'static>(&self, name: &str, f: F) {
+        let _d = dyn_enter(&self.dynamic);
+        env_sets(&self.env, name, named_func(name, f));
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<MalVal> {
+        let _d = dyn_enter(&self.dynamic);
+        env_get(&self.env, &Sym(name.to_string())).ok()
+    }
+
+    /// Prints a value the way the REPL shows results: pretty-printed
+    /// within `*print-right-margin*` columns, or on one line when
+    /// `*print-pretty*` is false.
+    pub fn pr_result(&self, val: &MalVal) -> String {
+        let _d = dyn_enter(&self.dynamic);
+        match self.get_global("*print-pretty*") {
+            None | Some(Nil) | Some(Bool(false)) => val.pr_str(true),
+            _ => match self.get_global("*print-right-margin*") {
+                Some(Int(w)) if w >= 0 => val.pprint_str(w as usize),
+                _ => val.pprint_str(80),
+            },
+        }
+    }
+
+    pub fn set_global<V: Into<MalVal>>(&self, name: &str, val: V) {
+        let _d = dyn_enter(&self.dynamic);
+        env_sets(&self.env, name, val.into());
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, SerializeMap, SerializeSeq};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Stream, Sym, Tagged, Vector,
+};
+use crate::types::{error, MalArgs, MalMap, MalRet, MalVal, MapKind};
+
+// How keywords (as values or map keys) are written as strings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeywordStyle {
+    Name,  // :a => "a"
+    Colon, // :a => ":a"
+}
+
+// A MalVal serialized with a given keyword style. Serialize for MalVal
+// itself uses KeywordStyle::Name.
+pub struct Serializable<'a> {
+    pub val: &'a MalVal,
+    pub keywords: KeywordStyle,
+}
+
+fn keyword_str(s: &str, keywords: KeywordStyle) -> String {
+    match (s.strip_prefix('\u{29e}'), keywords) {
+        (Some(name), KeywordStyle::Name) => name.to_string(),
+        (Some(name), KeywordStyle::Colon) => format!(":{}", name),
+        (None, _) => s.to_string(),
+    }
+}
+
+impl<'a> Serialize for Serializable<'a> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let sub = |val| Serializable {
+            val,
+            keywords: self.keywords,
+        };
+        match self.val {
+            Nil => s.serialize_unit(),
+            Bool(b) => s.serialize_bool(*b),
+            Int(i) => s.serialize_i64(*i),
+            Str(st) => s.serialize_str(&keyword_str(st, self.keywords)),
+            Sym(st) => s.serialize_str(st),
+            List(v, _) | Vector(v, _) => {
+                let mut seq = s.serialize_seq(Some(v.len()))?;
+                for mv in v.iter() {
+                    seq.serialize_element(&sub(mv))?;
+                }
+                seq.end()
+            }
+            Hash(hm, _) => {
+                let entries = hm.print_entries();
+                let mut map = s.serialize_map(Some(entries.len()))?;
+                // a keyword and a string can be written as the same key,
+                // as :a and "a" are with KeywordStyle::Name
+                let mut keys = HashSet::new();
+                for (k, v) in entries {
+                    let key = keyword_str(k, self.keywords);
+                    if !keys.insert(key.clone()) {
+                        return Err(ser::Error::custom(format!(
+                            "duplicate key {:?} in {}",
+                            key,
+                            self.val.pr_str(true)
+                        )));
+                    }
+                    map.serialize_entry(&key, &sub(v))?;
+                }
+                map.end()
+            }
+            // JSON has no tags: #inst "..." is written as its string
+            Tagged(_, v) => sub(v).serialize(s),
+            Func(_, _) | MalFunc { .. } => Err(ser::Error::custom(format!(
+                "cannot serialize function {}",
+                self.val.pr_str(true)
+            ))),
+            Atom(_) => Err(ser::Error::custom("cannot serialize an atom")),
+            Stream(_) => Err(ser::Error::custom(format!(
+                "cannot serialize stream {}",
+                self.val.pr_str(true)
+            ))),
+        }
+    }
+}
+
+impl Serialize for MalVal {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        Serializable {
+            val: self,
+            keywords: KeywordStyle::Name,
+        }
+        .serialize(s)
+    }
+}
+
+// Deserializes data into a MalVal: arrays become vectors and objects
+// become hash-maps, with keyword keys if keywordize is set. Deserialize
+// for MalVal uses string keys.
+#[derive(Clone, Copy)]
+pub struct MalSeed {
+    pub keywordize: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for MalSeed {
+    type Value = MalVal;
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<MalVal, D::Error> {
+        d.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for MalSeed {
+    type Value = MalVal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a value representable in mal")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<MalVal, E> {
+        Ok(Nil)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<MalVal, E> {
+        Ok(Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<MalVal, D::Error> {
+        self.deserialize(d)
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<MalVal, E> {
+        Ok(Bool(b))
+    }
+
+    fn visit_i64<E: de::Error>(self, i: i64) -> Result<MalVal, E> {
+        Ok(Int(i))
+    }
+
+    fn visit_u64<E: de::Error>(self, u: u64) -> Result<MalVal, E> {
+        if u > i64::MAX as u64 {
+            return Err(E::custom(format!("integer {} is out of range", u)));
+        }
+        Ok(Int(u as i64))
+    }
+
+    fn visit_f64<E: de::Error>(self, f: f64) -> Result<MalVal, E> {
+        // mal only has integers. Above 2^53 not every integer is an f64,
+        // so such a number (which may be an out-of-range integer serde
+        // parsed as a float) might already have been rounded.
+        if f.fract() == 0.0 && f.abs() <= 9007199254740992.0 {
+            Ok(Int(f as i64))
+        } else {
+            Err(E::custom(format!(
+                "cannot represent {} (mal has no floating point numbers)",
+                f
+            )))
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<MalVal, E> {
+        Ok(Str(s.to_string()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MalVal, A::Error> {
+        let mut v = vec![];
+        while let Some(mv) = seq.next_element_seed(self)? {
+            v.push(mv);
+        }
+        Ok(vector!(v))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MalVal, A::Error> {
+        let mut hm = MalMap::new(MapKind::Hash);
+        while let Some(k) = map.next_key::<String>()? {
+            let k = if self.keywordize {
+                format!("\u{29e}{}", k)
+            } else {
+                k
+            };
+            hm.insert(k, map.next_value_seed(self)?);
+        }
+        Ok(Hash(::std::rc::Rc::new(hm), ::std::rc::Rc::new(Nil)))
+    }
+}
+
+impl<'de> Deserialize<'de> for MalVal {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<MalVal, D::Error> {
+        MalSeed { keywordize: false }.deserialize(d)
+    }
+}
+
+// Looks up a keyword option in an optional options map
+fn opt<'a>(opts: Option<&'a MalVal>, name: &str) -> Option<&'a MalVal> {
+    match opts {
+        Some(Hash(hm, _)) => hm.get(&format!("\u{29e}{}", name)),
+        _ => None,
+    }
+}
+
+fn opt_true(opts: Option<&MalVal>, name: &str) -> bool {
+    !matches!(opt(opts, name), None | Some(Nil) | Some(Bool(false)))
+}
+
+// (json-encode val [{:pretty bool :keywords :name|:colon}])
+pub fn json_encode(a: MalArgs) -> MalRet {
+    let opts = a.get(1);
+    if let Some(o) = opts {
+        if !matches!(o, Hash(_, _) | Nil) {
+            return error("json-encode: options must be a map");
+        }
+    }
+    let keywords = match opt(opts, "keywords") {
+        None => KeywordStyle::Name,
+        Some(Str(s)) if s == "\u{29e}name" => KeywordStyle::Name,
+        Some(Str(s)) if s == "\u{29e}colon" => KeywordStyle::Colon,
+        Some(mv) => {
+            return error(&format!(
+                "json-encode: :keywords must be :name or :colon, got {}",
+                mv.pr_str(true)
+            ))
+        }
+    };
+    let val = Serializable {
+        val: &a[0],
+        keywords,
+    };
+    let res = if opt_true(opts, "pretty") {
+        serde_json::to_string_pretty(&val)
+    } else {
+        serde_json::to_string(&val)
+    };
+    res.map(Str)
+        .map_err(|e| ErrString(format!("json-encode: {}", e)))
+}
+
+// (json-decode str [{:keywordize bool}])
+pub fn json_decode(a: MalArgs) -> MalRet {
+    let s = match a[0] {
+        Str(ref s) => s,
+        _ => return error("json-decode: expecting (str) arg"),
+    };
+    let seed = MalSeed {
+        keywordize: opt_true(a.get(1), "keywordize"),
+    };
+    let mut de = serde_json::Deserializer::from_str(s);
+    seed.deserialize(&mut de)
+        .and_then(|mv| de.end().map(|_| mv))
+        .map_err(|e| ErrString(format!("json-decode: {}", e)))
+}
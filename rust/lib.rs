@@ -0,0 +1,27 @@
+//! mal as a library, for embedding the interpreter in Rust programs.
+//! [`Interpreter`] is the entry point; `types` has the value type and
+//! its conversions to and from Rust values.
+
+#[macro_use]
+extern crate lazy_static;
+extern crate fnv;
+extern crate itertools;
+extern crate regex;
+extern crate rustyline;
+extern crate serde;
+extern crate serde_json;
+
+#[macro_use]
+pub mod types;
+pub mod env;
+pub mod printer;
+pub mod reader;
+#[macro_use]
+pub mod core;
+mod eval;
+mod interpreter;
+pub mod json;
+mod pprint;
+
+pub use crate::interpreter::Interpreter;
+pub use crate::types::{MalArgs, MalErr, MalRet, MalVal};
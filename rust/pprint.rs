@@ -0,0 +1,284 @@
+// Pretty printer: values are turned into a document of text, line
+// breaks and groups, which is then laid out to fit a right margin in the
+// style of Wadler's "prettier printer". A group is printed on one line if
+// it fits, otherwise every line break directly inside it is taken.
+
+use crate::printer::PrintCtx;
+use crate::types::MalVal;
+use crate::types::MalVal::{Atom, Hash, List, Str, Sym, Tagged, Vector};
+
+enum Doc {
+    Text(String),
+    // a space when the enclosing group is flat, otherwise a newline
+    Line,
+    Concat(Vec<Doc>),
+    // indent by n more than the enclosing indentation
+    Nest(usize, Box<Doc>),
+    // indent to the column the document starts at
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+}
+
+use self::Doc::{Align, Concat, Group, Line, Nest, Text};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+// Forms whose first n arguments stay on the line of the operator, with
+// the remaining (body) forms indented by two:
+//   (let* [a 1
+//          b 2]
+//     body)
+const BODY_FORMS: &[(&str, usize)] = &[
+    ("def!", 1),
+    ("defmacro!", 1),
+    ("fn*", 1),
+    ("let*", 1),
+    ("binding", 1),
+    ("loop*", 1),
+    ("do", 0),
+    ("try*", 0),
+    ("catch*", 1),
+    ("finally", 0),
+];
+
+fn text(s: &str) -> Doc {
+    Text(s.to_string())
+}
+
+fn join(docs: Vec<Doc>) -> Doc {
+    let mut res = vec![];
+    for (i, d) in docs.into_iter().enumerate() {
+        if i > 0 {
+            res.push(Line);
+        }
+        res.push(d);
+    }
+    Concat(res)
+}
+
+// Like join, but each line break is only taken if the next item does
+// not fit, so short items are packed onto as few lines as possible
+fn fill(docs: Vec<Doc>) -> Doc {
+    let mut res = vec![];
+    for (i, d) in docs.into_iter().enumerate() {
+        if i > 0 {
+            res.push(Group(Box::new(Line)));
+        }
+        res.push(d);
+    }
+    Concat(res)
+}
+
+// open items... close, with the items aligned after open
+fn bracket(open: &str, items: Vec<Doc>, close: &str) -> Doc {
+    Group(Box::new(Concat(vec![
+        text(open),
+        Align(Box::new(join(items))),
+        text(close),
+    ])))
+}
+
+// The docs of the items of a collection, up to *print-length* of them
+fn item_docs(l: &[MalVal], ctx: &mut PrintCtx) -> Vec<Doc> {
+    let shown = ctx.shown(l.len());
+    let mut docs: Vec<Doc> = l[..shown].iter().map(|mv| doc(mv, ctx)).collect();
+    if shown < l.len() {
+        docs.push(text("..."));
+    }
+    docs
+}
+
+// A sequence of data: filled when none of the items are collections
+fn seq_doc(open: &str, l: &[MalVal], close: &str, ctx: &mut PrintCtx) -> Doc {
+    let docs = item_docs(l, ctx);
+    if l.iter()
+        .any(|mv| matches!(mv, List(..) | Vector(..) | Hash(..)))
+    {
+        return bracket(open, docs, close);
+    }
+    Group(Box::new(Concat(vec![
+        text(open),
+        Align(Box::new(fill(docs))),
+        text(close),
+    ])))
+}
+
+// let* and binding bindings are kept in pairs, one binding per line
+fn bindings_doc(mv: &MalVal, ctx: &mut PrintCtx) -> Doc {
+    match mv {
+        Vector(l, _) if l.len() % 2 == 0 && ctx.shown(l.len()) == l.len() => {
+            if !ctx.enter() {
+                return text("#");
+            }
+            let pairs = l
+                .chunks(2)
+                .map(|kv| Concat(vec![doc(&kv[0], ctx), text(" "), doc(&kv[1], ctx)]))
+                .collect();
+            ctx.leave();
+            bracket("[", pairs, "]")
+        }
+        _ => doc(mv, ctx),
+    }
+}
+
+fn list_doc(l: &[MalVal], ctx: &mut PrintCtx) -> Doc {
+    let head = match l.first() {
+        Some(Sym(s)) if ctx.shown(l.len()) == l.len() => s,
+        _ => return seq_doc("(", l, ")", ctx),
+    };
+    if l.len() == 1 {
+        return text(&format!("({})", head));
+    }
+    let args = &l[1..];
+    match BODY_FORMS.iter().find(|(f, _)| f == head) {
+        Some(&(_, n)) => {
+            let n = n.min(args.len());
+            let mut first = vec![text("("), text(head)];
+            for (i, a) in args[..n].iter().enumerate() {
+                first.push(text(" "));
+                first.push(
+                    if i == 0 && (head == "let*" || head == "binding" || head == "loop*") {
+                        bindings_doc(a, ctx)
+                    } else {
+                        doc(a, ctx)
+                    },
+                );
+            }
+            let body: Vec<Doc> = args[n..]
+                .iter()
+                .flat_map(|a| vec![Line, doc(a, ctx)])
+                .collect();
+            first.push(Nest(2, Box::new(Concat(body))));
+            first.push(text(")"));
+            Group(Box::new(Align(Box::new(Concat(first)))))
+        }
+        // a call: the arguments are aligned after the first one
+        None => Group(Box::new(Concat(vec![
+            text("("),
+            text(head),
+            text(" "),
+            Align(Box::new(join(args.iter().map(|a| doc(a, ctx)).collect()))),
+            text(")"),
+        ]))),
+    }
+}
+
+fn doc(mv: &MalVal, ctx: &mut PrintCtx) -> Doc {
+    let coll = match mv {
+        List(l, _) if l.is_empty() => return text("()"),
+        List(_, _) | Vector(_, _) | Hash(_, _) => mv,
+        Tagged(tag, v) => return Concat(vec![text(&format!("#{} ", tag)), doc(v, ctx)]),
+        Atom(a) => {
+            if let Err(label) = ctx.enter_atom(a) {
+                return text(&format!("#{}#", label));
+            }
+            let d = if ctx.enter() {
+                let d = Concat(vec![text("(atom "), doc(&a.borrow(), ctx), text(")")]);
+                ctx.leave();
+                d
+            } else {
+                text("#")
+            };
+            return match ctx.leave_atom() {
+                Some(label) => Concat(vec![text(&format!("#{}=", label)), d]),
+                None => d,
+            };
+        }
+        _ => return Text(mv.pr_str(true)),
+    };
+    if !ctx.enter() {
+        return text("#");
+    }
+    let d = match coll {
+        List(l, _) => list_doc(l, ctx),
+        Vector(l, _) => seq_doc("[", l, "]", ctx),
+        Hash(hm, _) => {
+            let entries = hm.print_entries();
+            let shown = ctx.shown(entries.len());
+            let mut docs: Vec<Doc> = entries[..shown]
+                .iter()
+                .map(|(k, v)| {
+                    Concat(vec![
+                        text(&Str(k.to_string()).pr_str(true)),
+                        text(" "),
+                        doc(v, ctx),
+                    ])
+                })
+                .collect();
+            if shown < entries.len() {
+                docs.push(text("..."));
+            }
+            bracket("{", docs, "}")
+        }
+        _ => unreachable!(),
+    };
+    ctx.leave();
+    d
+}
+
+// Whether d fits in width printed flat, followed by the rest of the
+// output up to its next line break
+fn fits(mut width: isize, d: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut items: Vec<(Mode, &Doc)> = vec![(Mode::Flat, d)];
+    let mut rest = rest.iter().rev();
+    while width >= 0 {
+        let (mode, d) = match items.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some(&(_, mode, d)) => (mode, d),
+                None => return true,
+            },
+        };
+        match d {
+            Text(s) => width -= s.chars().count() as isize,
+            Line if mode == Mode::Break => return true,
+            Line => width -= 1,
+            Concat(ds) => items.extend(ds.iter().rev().map(|d| (mode, d))),
+            Nest(_, d) | Align(d) | Group(d) => items.push((mode, d)),
+        }
+    }
+    false
+}
+
+fn layout(d: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut col = 0;
+    let mut stack: Vec<(usize, Mode, &Doc)> = vec![(0, Mode::Break, d)];
+    while let Some((indent, mode, d)) = stack.pop() {
+        match d {
+            Text(s) => {
+                out.push_str(s);
+                col += s.chars().count();
+            }
+            Line if mode == Mode::Flat => {
+                out.push(' ');
+                col += 1;
+            }
+            Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                col = indent;
+            }
+            Concat(ds) => stack.extend(ds.iter().rev().map(|d| (indent, mode, d))),
+            Nest(n, d) => stack.push((indent + n, mode, d)),
+            Align(d) => stack.push((col, mode, d)),
+            Group(d) => {
+                let fit = mode == Mode::Flat || fits(width as isize - col as isize, d, &stack);
+                stack.push((indent, if fit { Mode::Flat } else { Mode::Break }, d));
+            }
+        }
+    }
+    out
+}
+
+impl MalVal {
+    // Prints the value readably, breaking it over lines to keep within
+    // width columns where possible
+    pub fn pprint_str(&self, width: usize) -> String {
+        layout(&doc(self, &mut PrintCtx::new(true)), width)
+    }
+}
@@ -1,20 +1,122 @@
-use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-fn escape_str(s: &str) -> String {
+use crate::env::dyn_get;
+use crate::reader::EDN_SET;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Stream, Sym, Tagged, Vector,
+};
+use crate::types::{MalErr, MalStream, MalVal};
+
+// Escapes s so that the reader reads it back as the same string: the
+// usual control characters get their short escapes and any others are
+// written as \uXXXX. For EDN, which has no \0, NUL is \u0000 too.
+fn escape_str(s: &str, edn: bool) -> String {
     s.chars()
         .map(|c| match c {
             '"' => "\\\"".to_string(),
-            '\n' => "\\n".to_string(),
             '\\' => "\\\\".to_string(),
+            '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
+            '\0' if !edn => "\\0".to_string(),
+            '\u{8}' => "\\b".to_string(),
+            '\u{c}' => "\\f".to_string(),
+            _ if c.is_control() => format!("\\u{:04x}", c as u32),
             _ => c.to_string(),
         })
         .collect::<Vec<String>>()
         .join("")
 }
 
+// State for printing one value: the *print-length* and *print-level*
+// limits, how deep into collections the printer is, and the atoms it is
+// inside of. An atom that (indirectly) contains itself is printed as a
+// back-reference #1# to a label #1= on the enclosing atom, as in Common
+// Lisp, instead of recursing forever.
+pub struct PrintCtx {
+    pub readably: bool,
+    length: Option<usize>,
+    level: Option<usize>,
+    depth: usize,
+    atoms: Vec<(*const RefCell<MalVal>, Option<usize>)>,
+    labels: usize,
+}
+
+fn limit(name: &str) -> Option<usize> {
+    match dyn_get(name) {
+        Some(Int(n)) if n >= 0 => Some(n as usize),
+        _ => None,
+    }
+}
+
+impl PrintCtx {
+    pub fn new(readably: bool) -> PrintCtx {
+        PrintCtx {
+            length: limit("*print-length*"),
+            level: limit("*print-level*"),
+            ..PrintCtx::unlimited(readably)
+        }
+    }
+
+    pub fn unlimited(readably: bool) -> PrintCtx {
+        PrintCtx {
+            readably,
+            length: None,
+            level: None,
+            depth: 0,
+            atoms: vec![],
+            labels: 0,
+        }
+    }
+
+    // Enters a collection, unless that would go past *print-level*, in
+    // which case it is printed as #
+    pub fn enter(&mut self) -> bool {
+        if self.level.is_some_and(|l| self.depth >= l) {
+            return false;
+        }
+        self.depth += 1;
+        true
+    }
+
+    pub fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    // How many of n items to print; the rest are elided as ...
+    pub fn shown(&self, n: usize) -> usize {
+        self.length.map_or(n, |l| l.min(n))
+    }
+
+    // Enters an atom, or returns the label to refer back to it with if
+    // it is already being printed
+    pub fn enter_atom(&mut self, a: &Rc<RefCell<MalVal>>) -> Result<(), usize> {
+        let p = Rc::as_ptr(a);
+        if let Some(i) = self.atoms.iter().position(|&(q, _)| q == p) {
+            if self.atoms[i].1.is_none() {
+                self.labels += 1;
+                self.atoms[i].1 = Some(self.labels);
+            }
+            return Err(self.atoms[i].1.unwrap());
+        }
+        self.atoms.push((p, None));
+        Ok(())
+    }
+
+    // Leaves the current atom, returning its label if it was referred to
+    pub fn leave_atom(&mut self) -> Option<usize> {
+        self.atoms.pop().and_then(|(_, label)| label)
+    }
+}
+
 impl MalVal {
     pub fn pr_str(&self, print_readably: bool) -> String {
+        self.pr(&mut PrintCtx::new(print_readably))
+    }
+
+    fn pr(&self, ctx: &mut PrintCtx) -> String {
         match self {
             Nil => String::from("nil"),
             Bool(true) => String::from("true"),
@@ -22,40 +124,141 @@ impl MalVal {
             Int(i) => format!("{}", i),
             //Float(f)    => format!("{}", f),
             Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
-                    format!("\"{}\"", escape_str(s))
+                if let Some(kw) = s.strip_prefix('\u{29e}') {
+                    format!(":{}", kw)
+                } else if ctx.readably {
+                    format!("\"{}\"", escape_str(s, false))
                 } else {
                     s.clone()
                 }
             }
             Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+            List(l, _) => pr_coll(ctx, l, 1, "(", ")"),
+            Vector(l, _) => pr_coll(ctx, l, 1, "[", "]"),
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
-                    .iter()
+                    .print_entries()
+                    .into_iter()
                     .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
                     .collect();
-                pr_seq(&l, print_readably, "{", "}", " ")
+                pr_coll(ctx, &l, 2, "{", "}")
             }
-            Func(f, _) => format!("#<fn {:?}>", f),
+            Func(nf, _) => format!("#<fn {}>", nf.name),
+            // #<fn name [params] file:line>, leaving out what is unknown
             MalFunc {
-                ast: a, params: p, ..
-            } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
-            Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+                params,
+                is_macro,
+                info,
+                ..
+            } => {
+                let mut s = String::from(if *is_macro { "#<macro" } else { "#<fn" });
+                if let Some(name) = &info.name {
+                    s = format!("{} {}", s, name);
+                }
+                if let List(p, _) | Vector(p, _) = &**params {
+                    s = format!("{} {}", s, pr_seq(p, true, "[", "]", " "));
+                }
+                if let (Some(file), Some(line)) = (&info.file, info.line) {
+                    s = format!("{} {}:{}", s, file, line);
+                }
+                s + ">"
+            }
+            Tagged(tag, v) => format!("#{} {}", tag, v.pr(ctx)),
+            Atom(a) => {
+                if let Err(label) = ctx.enter_atom(a) {
+                    return format!("#{}#", label);
+                }
+                let s = if ctx.enter() {
+                    let readably = ctx.readably;
+                    ctx.readably = true;
+                    let s = format!("(atom {})", a.borrow().pr(ctx));
+                    ctx.readably = readably;
+                    ctx.leave();
+                    s
+                } else {
+                    "#".to_string()
+                };
+                match ctx.leave_atom() {
+                    Some(label) => format!("#{}={}", label, s),
+                    None => s,
+                }
+            }
+            Stream(st) => match &*st.borrow() {
+                MalStream::Closed { name } => format!("#<stream {} (closed)>", name),
+                st => format!("#<stream {}>", st.name()),
+            },
         }
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
-    print_readably: bool,
-    start: &str,
-    end: &str,
-    join: &str,
-) -> String {
+// Prints a collection of items, where each element takes up width items
+// (2 for the key/value pairs of a map)
+fn pr_coll(ctx: &mut PrintCtx, items: &[MalVal], width: usize, start: &str, end: &str) -> String {
+    if !ctx.enter() {
+        return "#".to_string();
+    }
+    let shown = ctx.shown(items.len() / width) * width;
+    let mut strs: Vec<String> = items[..shown].iter().map(|x| x.pr(ctx)).collect();
+    if shown < items.len() {
+        strs.push("...".to_string());
+    }
+    ctx.leave();
+    format!("{}{}{}", start, strs.join(" "), end)
+}
+
+impl MalVal {
+    // Writes the value as EDN. Only data can be written: functions,
+    // atoms and streams are an error rather than unreadable output.
+    pub fn edn_str(&self) -> Result<String, MalErr> {
+        match self {
+            List(l, _) => edn_seq(l, "(", ")"),
+            Vector(l, meta) => match &**meta {
+                Hash(hm, _) if hm.get(EDN_SET) == Some(&Bool(true)) => edn_seq(l, "#{", "}"),
+                _ => edn_seq(l, "[", "]"),
+            },
+            Hash(hm, _) => {
+                let l: Vec<MalVal> = hm
+                    .print_entries()
+                    .into_iter()
+                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .collect();
+                edn_seq(&l, "{", "}")
+            }
+            Tagged(tag, v) => Ok(format!("#{} {}", tag, v.edn_str()?)),
+            Str(s) if !self.keyword_q() => Ok(format!("\"{}\"", escape_str(s, true))),
+            Func(_, _) | MalFunc { .. } | Atom(_) | Stream(_) => Err(ErrString(format!(
+                "cannot write {} as EDN",
+                self.pr_str(true)
+            ))),
+            _ => Ok(self.pr_str(true)),
+        }
+    }
+}
+
+fn edn_seq(seq: &[MalVal], start: &str, end: &str) -> Result<String, MalErr> {
+    let strs = seq
+        .iter()
+        .map(|x| x.edn_str())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("{}{}{}", start, strs.join(" "), end))
+}
+
+pub fn pr_seq(seq: &[MalVal], print_readably: bool, start: &str, end: &str, join: &str) -> String {
     let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
     format!("{}{}{}", start, strs.join(join), end)
 }
+
+// The string conversion done by str, which ignores *print-length* and
+// *print-level*: those limit output meant for people, whereas str builds
+// strings from values in full.
+pub fn str_seq(seq: &[MalVal]) -> String {
+    seq.iter()
+        .map(|x| x.pr(&mut PrintCtx::unlimited(false)))
+        .collect()
+}
+
+// The readable form of mv in full, also ignoring the limits, as used by
+// group-by and frequencies to key values that are not strings
+pub fn pr_full(mv: &MalVal) -> String {
+    mv.pr(&mut PrintCtx::unlimited(true))
+}
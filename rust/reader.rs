@@ -1,19 +1,182 @@
-use regex::{Captures, Regex};
+use fnv::FnvHashMap;
+use regex::Regex;
 use std::rc::Rc;
 
+use crate::env::dyn_get;
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Hash, Int, List, Nil, Str, Sym, Tagged, Vector};
+use crate::types::{error, func, hash_map, MalErr, MalMap, MalRet, MalVal, MapKind};
 
+// Handlers for EDN tagged literals, keyed by tag name without the #.
+// A handler is called with the form following the tag and its result
+// replaces the tagged form. Tags with no handler are passed to default
+// as (tag form), or are an error when there is no default.
 #[derive(Debug, Clone)]
-struct Reader {
+pub struct TagHandlers {
+    pub tags: FnvHashMap<String, MalVal>,
+    pub default: Option<MalVal>,
+}
+
+// The built-in tags: #inst and #uuid check their string and keep it
+// tagged, so values written back with edn_str round-trip.
+impl Default for TagHandlers {
+    fn default() -> TagHandlers {
+        let mut tags = FnvHashMap::default();
+        tags.insert("inst".to_string(), func(read_inst));
+        tags.insert("uuid".to_string(), func(read_uuid));
+        TagHandlers {
+            tags,
+            default: None,
+        }
+    }
+}
+
+fn read_inst(a: Vec<MalVal>) -> MalRet {
+    lazy_static! {
+        static ref INST_RE: Regex = Regex::new(
+            r"^\d{4}(-\d{2}(-\d{2}(T\d{2}(:\d{2}(:\d{2}(\.\d+)?)?)?(Z|[-+]\d{2}:\d{2})?)?)?)?$"
+        )
+        .unwrap();
+    }
+    match a[0] {
+        Str(ref s) if INST_RE.is_match(s) => Ok(Tagged("inst".to_string(), Rc::new(a[0].clone()))),
+        _ => error(&format!(
+            "#inst expects an RFC 3339 timestamp string, got {}",
+            a[0].pr_str(true)
+        )),
+    }
+}
+
+fn read_uuid(a: Vec<MalVal>) -> MalRet {
+    lazy_static! {
+        static ref UUID_RE: Regex =
+            Regex::new(r"^[0-9a-fA-F]{8}(-[0-9a-fA-F]{4}){3}-[0-9a-fA-F]{12}$").unwrap();
+    }
+    match a[0] {
+        Str(ref s) if UUID_RE.is_match(s) => Ok(Tagged("uuid".to_string(), Rc::new(a[0].clone()))),
+        _ => error(&format!(
+            "#uuid expects a UUID string, got {}",
+            a[0].pr_str(true)
+        )),
+    }
+}
+
+// Limits on what the reader accepts, so that reading untrusted input
+// fails with an error rather than exhausting the stack or memory. None
+// is no limit.
+#[derive(Debug, Clone)]
+pub struct ReadLimits {
+    // how deeply forms may nest
+    pub depth: Option<usize>,
+    pub tokens: Option<usize>,
+    // the characters in a string
+    pub string_length: Option<usize>,
+    // the forms in a collection
+    pub items: Option<usize>,
+}
+
+// The depth limit keeps the recursive reader well within the stack of
+// the main thread, even in a debug build
+impl Default for ReadLimits {
+    fn default() -> ReadLimits {
+        ReadLimits {
+            depth: Some(256),
+            tokens: Some(10_000_000),
+            string_length: Some(10_000_000),
+            items: Some(1_000_000),
+        }
+    }
+}
+
+impl ReadLimits {
+    // The limits set in *reader-limits*, which read-string,
+    // read-all-string, edn/read-string and load-file read with: a map
+    // with the keys :depth, :tokens, :string-length and :items. A key
+    // that is missing keeps its default and one that is nil has no limit.
+    pub fn current() -> Result<ReadLimits, MalErr> {
+        let mut limits = ReadLimits::default();
+        let hm = match dyn_get("*reader-limits*") {
+            Some(Hash(hm, _)) => hm,
+            Some(Nil) | None => return Ok(limits),
+            Some(v) => {
+                return Err(ErrString(format!(
+                    "*reader-limits* must be a map, got {}",
+                    v.pr_str(true)
+                )))
+            }
+        };
+        for (key, limit) in &mut [
+            ("depth", &mut limits.depth),
+            ("tokens", &mut limits.tokens),
+            ("string-length", &mut limits.string_length),
+            ("items", &mut limits.items),
+        ] {
+            match hm.get(&format!("\u{29e}{}", key)) {
+                None => (),
+                Some(Nil) => **limit = None,
+                Some(Int(n)) if *n >= 0 => **limit = Some(*n as usize),
+                Some(v) => {
+                    return Err(ErrString(format!(
+                        "*reader-limits* :{} must be a non-negative integer or nil, got {}",
+                        key,
+                        v.pr_str(true)
+                    )))
+                }
+            }
+        }
+        Ok(limits)
+    }
+}
+
+fn check_limit(limit: Option<usize>, n: usize, what: &str) -> Result<(), MalErr> {
+    match limit {
+        Some(max) if n > max => Err(ErrString(format!(
+            "reader limit exceeded: more than {} {}",
+            max, what
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// positions holds the line and column of each token, and file the name
+// of the source being read, if it has one. edn holds the tag handlers
+// when reading EDN rather than mal code. in_anon_fn is set while reading
+// the body of a #(...). depth is how deeply the form being read is
+// nested.
+#[derive(Debug, Clone)]
+struct Reader<'a> {
     tokens: Vec<String>,
+    positions: Vec<(i64, i64)>,
     pos: usize,
+    file: Option<&'a str>,
+    edn: Option<&'a TagHandlers>,
+    in_anon_fn: bool,
+    limits: ReadLimits,
+    depth: usize,
 }
 
-impl Reader {
+impl<'a> Reader<'a> {
+    fn new(
+        str: &str,
+        file: Option<&'a str>,
+        edn: Option<&'a TagHandlers>,
+        limits: ReadLimits,
+    ) -> Result<Reader<'a>, MalErr> {
+        let (tokens, positions) = tokenize(str, edn.is_some(), &limits)?;
+        //println!("tokens: {:?}", tokens);
+        Ok(Reader {
+            pos: 0,
+            tokens,
+            positions,
+            file,
+            edn,
+            in_anon_fn: false,
+            limits,
+            depth: 0,
+        })
+    }
     fn next(&mut self) -> Result<String, MalErr> {
-        self.pos = self.pos + 1;
+        self.pos += 1;
         Ok(self
             .tokens
             .get(self.pos - 1)
@@ -29,32 +192,126 @@ impl Reader {
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
+// The tokens and their positions
+type Tokens = (Vec<String>, Vec<(i64, i64)>);
+
+// A #| block comment |# is a single token, as they nest. An
+// unterminated one is the token #| on its own.
+// EDN adds #{ sets and \c characters to the mal tokens.
+fn tokenize(str: &str, edn: bool, limits: &ReadLimits) -> Result<Tokens, MalErr> {
     lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+        static ref MAL_RE: Regex = Regex::new(
+            r###"[\s,]*(~@|#[_(|]|#\?@?\(|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+        )
+        .unwrap();
+        static ref EDN_RE: Regex = Regex::new(
+            r###"[\s,]*(~@|#[{_(|]|#\?@?\(|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|\\[^\s][^\s\[\]{}('"`,;)]*|[^\s\[\]{}('"`,;)]+)"###
         )
         .unwrap();
     }
+    let re: &Regex = if edn { &EDN_RE } else { &MAL_RE };
 
-    let mut res = vec![];
-    for cap in RE.captures_iter(str) {
+    let (mut res, mut positions) = (vec![], vec![]);
+    // the line and column (counted from 0) of scanned
+    let (mut line, mut column, mut scanned) = (1, 0, 0);
+    let mut pos = 0;
+    while let Some(cap) = re.captures(&str[pos..]) {
+        let start = pos + cap.get(1).unwrap().start();
+        let mut end = pos + cap.get(0).unwrap().end();
+        let skipped = &str[scanned..start];
+        match skipped.rfind('\n') {
+            Some(i) => {
+                line += skipped.matches('\n').count() as i64;
+                column = skipped[i + 1..].chars().count();
+            }
+            None => column += skipped.chars().count(),
+        }
+        scanned = start;
+        if &cap[1] == "#|" {
+            end = block_comment_end(str, end).unwrap_or(end);
+        }
+        pos = end;
         if cap[1].starts_with(";") {
             continue;
         }
-        res.push(String::from(&cap[1]));
+        check_limit(limits.tokens, res.len() + 1, "tokens")?;
+        res.push(String::from(&str[start..end]));
+        positions.push((line, column as i64 + 1));
     }
-    res
+    Ok((res, positions))
 }
 
-fn unescape_str(s: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+// The end of the block comment whose body starts at start
+fn block_comment_end(str: &str, start: usize) -> Option<usize> {
+    let mut depth = 1;
+    let mut i = start;
+    while depth > 0 {
+        i += str[i..].find(['#', '|'])?;
+        if str[i..].starts_with("#|") {
+            depth += 1;
+            i += 2;
+        } else if str[i..].starts_with("|#") {
+            depth -= 1;
+            i += 2;
+        } else {
+            i += 1;
+        }
     }
-    RE.replace_all(&s, |caps: &Captures| {
-        format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
-    })
-    .to_string()
+    Some(i)
+}
+
+// The escapes are those printer::escape_str writes: \n \t \r \0 \b \f
+// \" \\, \uXXXX and \u{X...} for any other character. Anything else
+// after a backslash is an error rather than being read as itself.
+fn unescape_str(s: &str) -> Result<String, MalErr> {
+    let mut res = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        res.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('u') => match unicode_escape(chars.as_str()) {
+                Some((c, len)) => {
+                    chars = chars.as_str()[len..].chars();
+                    c
+                }
+                None => return Err(ErrString("invalid '\\u' escape".to_string())),
+            },
+            Some(e) => return Err(ErrString(format!("invalid escape '\\{}'", e))),
+            None => return Err(ErrString("expected '\"', got EOF".to_string())),
+        });
+    }
+    Ok(res)
+}
+
+// Reads the XXXX or {X...} following a \u, returning the character and
+// the length of the escape
+fn unicode_escape(rest: &str) -> Option<(char, usize)> {
+    let (hex, len) = match rest.strip_prefix('{') {
+        Some(r) => {
+            let end = r.find('}')?;
+            (
+                r.get(..end).filter(|h| (1..=6).contains(&h.len()))?,
+                end + 2,
+            )
+        }
+        None => (rest.get(..4)?, 4),
+    };
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let c = std::char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+    Some((c, len))
 }
 
 fn read_atom(rdr: &mut Reader) -> MalRet {
@@ -71,11 +328,17 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
             if INT_RE.is_match(&token) {
                 Ok(Int(token.parse().unwrap()))
             } else if STR_RE.is_match(&token) {
-                Ok(Str(unescape_str(&token[1..token.len() - 1])))
+                let s = unescape_str(&token[1..token.len() - 1])?;
+                check_limit(
+                    rdr.limits.string_length,
+                    s.chars().count(),
+                    "characters in a string",
+                )?;
+                Ok(Str(s))
             } else if token.starts_with("\"") {
                 error("expected '\"', got EOF")
-            } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
+            } else if let Some(kw) = token.strip_prefix(':') {
+                Ok(Str(format!("\u{29e}{}", kw)))
             } else {
                 Ok(Sym(token.to_string()))
             }
@@ -83,29 +346,309 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
     }
 }
 
-fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
+// Reads the forms up to the closing token end, which is consumed
+fn read_forms(rdr: &mut Reader, end: &str) -> Result<Vec<MalVal>, MalErr> {
     let mut seq: Vec<MalVal> = vec![];
     rdr.next()?;
     loop {
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => return Err(ErrString(format!("expected '{}', got EOF", end))),
         };
         if token == end {
             break;
         }
-        seq.push(read_form(rdr)?)
+        match read_item(rdr)? {
+            Item::Skip => (),
+            Item::Form(form) => seq.push(form),
+            Item::Splice(forms) => seq.extend(forms),
+        }
+        check_limit(rdr.limits.items, seq.len(), "items in a collection")?;
     }
     let _ = rdr.next();
+    Ok(seq)
+}
+
+// Lists read as code from a named source get their position as
+// metadata, like Clojure: {:line l :column c :file f}. Lists read without
+// a file name, such as REPL input or read-string, get no metadata, so
+// that (meta '(a b)) is nil and reading costs no extra map per list.
+fn position(rdr: &Reader) -> MalRet {
+    let file = match rdr.file {
+        Some(f) => f,
+        None => return Ok(Nil),
+    };
+    let (line, column) = rdr.positions[rdr.pos];
+    hash_map(vec![
+        Str("\u{29e}line".to_string()),
+        Int(line),
+        Str("\u{29e}column".to_string()),
+        Int(column),
+        Str("\u{29e}file".to_string()),
+        Str(file.to_string()),
+    ])
+}
+
+fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
+    let meta = match (end, rdr.edn) {
+        (")", None) => position(rdr)?,
+        _ => Nil,
+    };
+    let seq = read_forms(rdr, end)?;
     match end {
-        ")" => Ok(list!(seq)),
+        ")" => Ok(List(Rc::new(seq), Rc::new(meta))),
         "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
+        "}" => {
+            // EDN, like its sets, doesn't allow a key more than once
+            if rdr.edn.is_some() {
+                check_distinct(seq.iter().step_by(2).collect(), "map key")?;
+            }
+            hash_map(seq)
+        }
         _ => error("read_seq unknown end value"),
     }
 }
 
+// Where a # syntax can be used
+#[derive(Debug, PartialEq)]
+enum Syntax {
+    Mal,
+    Edn,
+    Both,
+}
+
+// What reading the next item gave
+enum Item {
+    // something skipped, such as a comment
+    Skip,
+    Form(MalVal),
+    // forms to splice into the enclosing collection
+    Splice(Vec<MalVal>),
+}
+
+// The metadata key marking a vector read from an EDN #{...} set
+pub const EDN_SET: &str = "\u{29e}edn/set";
+
+type DispatchFn = fn(&mut Reader) -> Result<Item, MalErr>;
+
+// The # dispatch table: the token each syntax starts with, where it can
+// be used and the function reading it, called with the token not yet
+// consumed. (EDN tagged literals, #tag form, are not in the table since
+// their tokens are the tags.)
+const DISPATCH: &[(&str, Syntax, DispatchFn)] = &[
+    ("#_", Syntax::Both, read_discard),
+    ("#|", Syntax::Mal, read_block_comment),
+    ("#(", Syntax::Mal, read_anon_fn),
+    ("#?@(", Syntax::Mal, read_conditional),
+    ("#?(", Syntax::Mal, read_conditional),
+    ("#{", Syntax::Edn, read_set),
+];
+
+fn read_item(rdr: &mut Reader) -> Result<Item, MalErr> {
+    rdr.depth += 1;
+    let item =
+        check_limit(rdr.limits.depth, rdr.depth, "levels of nesting").and_then(|_| dispatch(rdr));
+    rdr.depth -= 1;
+    item
+}
+
+fn dispatch(rdr: &mut Reader) -> Result<Item, MalErr> {
+    let token = rdr.peek()?;
+    let edn = rdr.edn.is_some();
+    match DISPATCH.iter().find(|(t, _, _)| token.starts_with(t)) {
+        Some((_, syntax, f)) => match (syntax, edn) {
+            (Syntax::Both, _) | (Syntax::Mal, false) | (Syntax::Edn, true) => f(rdr),
+            (_, true) => Err(ErrString(format!(
+                "{} is not valid EDN",
+                dispatch_name(&token)
+            ))),
+            (_, false) => Err(ErrString(format!(
+                "{} is only valid in EDN",
+                dispatch_name(&token)
+            ))),
+        },
+        None if edn => read_edn_form(rdr).map(Item::Form),
+        None => read_mal_form(rdr).map(Item::Form),
+    }
+}
+
+// The dispatch token without its opening bracket, for error messages
+fn dispatch_name(token: &str) -> &str {
+    match token {
+        "#(" | "#|" => &token[..2],
+        _ => token.trim_end_matches(['(', '{']),
+    }
+}
+
 fn read_form(rdr: &mut Reader) -> MalRet {
+    loop {
+        match read_item(rdr)? {
+            Item::Skip => (),
+            Item::Form(form) => return Ok(form),
+            Item::Splice(_) => return error("#?@ can only splice into a collection"),
+        }
+    }
+}
+
+// The form following the token just read, which must be there
+fn read_after(rdr: &mut Reader, token: &str) -> MalRet {
+    match read_form(rdr) {
+        Err(ErrString(ref s)) if s == "underflow" => {
+            error(&format!("expected a form after {}, got EOF", token))
+        }
+        r => r,
+    }
+}
+
+// #_ form: the form is read, then discarded
+fn read_discard(rdr: &mut Reader) -> Result<Item, MalErr> {
+    let _ = rdr.next();
+    read_after(rdr, "#_")?;
+    Ok(Item::Skip)
+}
+
+fn read_block_comment(rdr: &mut Reader) -> Result<Item, MalErr> {
+    if rdr.next()? == "#|" {
+        return Err(ErrString("expected '|#', got EOF".to_string()));
+    }
+    Ok(Item::Skip)
+}
+
+// The features reader conditionals select on: the keyword naming
+// *host-language*, those in *reader-features*, and :default
+fn features() -> Vec<MalVal> {
+    let mut features = vec![Str("\u{29e}default".to_string())];
+    if let Some(Str(host)) = dyn_get("*host-language*") {
+        features.push(Str(format!("\u{29e}{}", host)));
+    }
+    match dyn_get("*reader-features*") {
+        Some(List(l, _)) | Some(Vector(l, _)) => features.extend(l.iter().cloned()),
+        _ => (),
+    }
+    features
+}
+
+// #?(feature form ...) is the form of the first feature that is active,
+// or nothing if none are. #?@(feature [form ...] ...) splices the forms
+// of the list or vector chosen into the enclosing collection.
+fn read_conditional(rdr: &mut Reader) -> Result<Item, MalErr> {
+    let splice = rdr.peek()? == "#?@(";
+    let clauses = read_forms(rdr, ")")?;
+    if clauses.len() % 2 != 0 {
+        return Err(ErrString(
+            "reader conditional needs an even number of forms".to_string(),
+        ));
+    }
+    let features = features();
+    for clause in clauses.chunks(2) {
+        match &clause[0] {
+            Str(k) if k.starts_with("\u{29e}") => (),
+            f => {
+                return Err(ErrString(format!(
+                    "reader conditional feature must be a keyword, got {}",
+                    f.pr_str(true)
+                )))
+            }
+        }
+        if !features.contains(&clause[0]) {
+            continue;
+        }
+        return match (splice, &clause[1]) {
+            (false, form) => Ok(Item::Form(form.clone())),
+            (true, List(l, _)) | (true, Vector(l, _)) => Ok(Item::Splice(l.to_vec())),
+            (true, form) => Err(ErrString(format!(
+                "#?@ must splice a list or vector, got {}",
+                form.pr_str(true)
+            ))),
+        };
+    }
+    Ok(Item::Skip)
+}
+
+// #(body ...) is (fn* [%1 ... %n & %&] (body ...)), where n is the
+// highest %n used in the body and % is short for %1
+fn read_anon_fn(rdr: &mut Reader) -> Result<Item, MalErr> {
+    if rdr.in_anon_fn {
+        return Err(ErrString("nested #()s are not allowed".to_string()));
+    }
+    let meta = Rc::new(position(rdr)?);
+    rdr.in_anon_fn = true;
+    let body = read_forms(rdr, ")");
+    rdr.in_anon_fn = false;
+    let (mut n, mut rest) = (0, false);
+    let body = anon_fn_args(&List(Rc::new(body?), meta.clone()), &mut n, &mut rest);
+    let mut params: Vec<MalVal> = (1..=n).map(|i| Sym(format!("%{}", i))).collect();
+    if rest {
+        params.push(Sym("&".to_string()));
+        params.push(Sym("%&".to_string()));
+    }
+    Ok(Item::Form(List(
+        Rc::new(vec![Sym("fn*".to_string()), vector!(params), body]),
+        meta,
+    )))
+}
+
+// Replaces % with %1 in mv, recording the highest %n and whether %& is
+// used
+fn anon_fn_args(mv: &MalVal, n: &mut usize, rest: &mut bool) -> MalVal {
+    match mv {
+        Sym(s) if s == "%" => {
+            *n = (*n).max(1);
+            Sym("%1".to_string())
+        }
+        Sym(s) if s == "%&" => {
+            *rest = true;
+            mv.clone()
+        }
+        Sym(s) => {
+            if let Some(i) = s.strip_prefix('%').and_then(|i| i.parse::<usize>().ok()) {
+                *n = (*n).max(i);
+            }
+            mv.clone()
+        }
+        List(l, meta) => List(
+            Rc::new(l.iter().map(|x| anon_fn_args(x, n, rest)).collect()),
+            meta.clone(),
+        ),
+        Vector(l, meta) => Vector(
+            Rc::new(l.iter().map(|x| anon_fn_args(x, n, rest)).collect()),
+            meta.clone(),
+        ),
+        Hash(hm, meta) => {
+            let mut new_hm = MalMap::new(hm.kind());
+            for (k, v) in hm.iter() {
+                new_hm.insert(k.to_string(), anon_fn_args(v, n, rest));
+            }
+            Hash(Rc::new(new_hm), meta.clone())
+        }
+        _ => mv.clone(),
+    }
+}
+
+// #{...}: mal has no sets, so a set is read as a vector of its elements,
+// which must be distinct, marked as a set in its metadata so that
+// edn/write-string writes it back as #{...}
+fn read_set(rdr: &mut Reader) -> Result<Item, MalErr> {
+    let set = read_forms(rdr, "}")?;
+    check_distinct(set.iter().collect(), "set element")?;
+    let meta = hash_map(vec![Str(EDN_SET.to_string()), Bool(true)])?;
+    Ok(Item::Form(Vector(Rc::new(set), Rc::new(meta))))
+}
+
+// Fails with "duplicate what: x" if x occurs more than once in items
+fn check_distinct(mut items: Vec<&MalVal>, what: &str) -> Result<(), MalErr> {
+    items.sort_by(|a, b| a.compare(b));
+    match items.windows(2).find(|w| w[0] == w[1]) {
+        Some(w) => Err(ErrString(format!(
+            "duplicate {}: {}",
+            what,
+            w[0].pr_str(true)
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn read_mal_form(rdr: &mut Reader) -> MalRet {
     let token = rdr.peek()?;
     match &token[..] {
         "'" => {
@@ -125,9 +668,12 @@ fn read_form(rdr: &mut Reader) -> MalRet {
             Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
         }
         "^" => {
-            let _ = rdr.next();
-            let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            let (form, meta) = read_meta(rdr)?;
+            Ok(list![
+                Sym("with-meta".to_string()),
+                form,
+                Hash(Rc::new(meta), Rc::new(Nil))
+            ])
         }
         "@" => {
             let _ = rdr.next();
@@ -143,14 +689,235 @@ fn read_form(rdr: &mut Reader) -> MalRet {
     }
 }
 
+// ^meta form: meta is a map, or short for one: ^:kw is {:kw true} and
+// ^Sym or ^"Str" is {:tag Sym}. Metadata given more than once, as in
+// ^:a ^:b form, is merged, the outer taking precedence like Clojure.
+fn read_meta(rdr: &mut Reader) -> Result<(MalVal, MalMap), MalErr> {
+    let _ = rdr.next();
+    let kvs = match read_after(rdr, "^")? {
+        Hash(hm, _) => hm.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+        Str(ref kw) if kw.starts_with('\u{29e}') => vec![(kw.to_string(), Bool(true))],
+        tag @ Sym(_) => vec![(
+            "\u{29e}tag".to_string(),
+            list![Sym("quote".to_string()), tag],
+        )],
+        tag @ Str(_) => vec![("\u{29e}tag".to_string(), tag)],
+        m => {
+            return Err(ErrString(format!(
+                "metadata must be a map, keyword, symbol or string, got {}",
+                m.pr_str(true)
+            )))
+        }
+    };
+    let (form, mut meta) = match rdr.peek() {
+        // chained metadata recurses here rather than through read_item,
+        // so it counts towards the nesting limit itself
+        Ok(ref t) if t == "^" => {
+            rdr.depth += 1;
+            let res = check_limit(rdr.limits.depth, rdr.depth, "levels of nesting")
+                .and_then(|_| read_meta(rdr));
+            rdr.depth -= 1;
+            res?
+        }
+        _ => (read_after(rdr, "^meta")?, MalMap::new(MapKind::Hash)),
+    };
+    for (k, v) in kvs {
+        meta.insert(k, v);
+    }
+    Ok((form, meta))
+}
+
+// EDN has no reader macros; it adds sets, #_ discards, character
+// literals and tagged literals to the plain data syntax shared with mal.
+fn read_edn_form(rdr: &mut Reader) -> MalRet {
+    let token = rdr.peek()?;
+    match &token[..] {
+        "'" | "`" | "~" | "~@" | "^" | "@" => error(&format!("{} is not valid EDN", token)),
+        ")" => error("unexpected ')'"),
+        "(" => read_seq(rdr, ")"),
+        "]" => error("unexpected ']'"),
+        "[" => read_seq(rdr, "]"),
+        "}" => error("unexpected '}'"),
+        "{" => read_seq(rdr, "}"),
+        t if t.starts_with('#') => read_tagged(rdr),
+        _ => read_edn_atom(rdr),
+    }
+}
+
+fn read_tagged(rdr: &mut Reader) -> MalRet {
+    let token = rdr.next()?;
+    let tag = &token[1..];
+    if !tag.starts_with(char::is_alphabetic) {
+        return error(&format!("invalid dispatch '{}'", token));
+    }
+    let form = read_after(rdr, &token)?;
+    let handlers = rdr.edn.unwrap();
+    match (handlers.tags.get(tag), &handlers.default) {
+        (Some(f), _) => f.apply(vec![form]),
+        (None, Some(f)) => f.apply(vec![Sym(tag.to_string()), form]),
+        (None, None) => error(&format!("no reader function for tag #{}", tag)),
+    }
+}
+
+fn read_edn_atom(rdr: &mut Reader) -> MalRet {
+    lazy_static! {
+        static ref INT_RE: Regex = Regex::new(r"^[-+]?[0-9]+N?$").unwrap();
+        static ref FLOAT_RE: Regex =
+            Regex::new(r"^[-+]?[0-9]+(\.[0-9]*)?([eE][-+]?[0-9]+)?M?$").unwrap();
+    }
+    let token = rdr.peek()?;
+    if token.starts_with('"') || token == "nil" || token == "true" || token == "false" {
+        return read_atom(rdr);
+    }
+    let _ = rdr.next();
+    if INT_RE.is_match(&token) {
+        match token.trim_start_matches('+').trim_end_matches('N').parse() {
+            Ok(i) => Ok(Int(i)),
+            Err(_) => error(&format!("integer out of range: {}", token)),
+        }
+    } else if FLOAT_RE.is_match(&token) {
+        error(&format!(
+            "floating point numbers are not supported: {}",
+            token
+        ))
+    } else if let Some(c) = token.strip_prefix('\\') {
+        read_char(c)
+    } else if let Some(kw) = token.strip_prefix(':') {
+        if kw.is_empty() || kw.starts_with(':') || kw.ends_with('/') {
+            return error(&format!("invalid keyword: {}", token));
+        }
+        Ok(Str(format!("\u{29e}{}", kw)))
+    } else {
+        Ok(Sym(token))
+    }
+}
+
+// Characters are read as one-character strings, since mal has no
+// character type
+fn read_char(name: &str) -> MalRet {
+    let c = match name {
+        "newline" => '\n',
+        "return" => '\r',
+        "space" => ' ',
+        "tab" => '\t',
+        "formfeed" => '\u{c}',
+        "backspace" => '\u{8}',
+        _ if name.chars().count() == 1 => name.chars().next().unwrap(),
+        _ if name.len() == 5 && name.starts_with('u') => {
+            match u32::from_str_radix(&name[1..], 16)
+                .ok()
+                .and_then(std::char::from_u32)
+            {
+                Some(c) => c,
+                None => return error(&format!("invalid character literal: \\{}", name)),
+            }
+        }
+        _ => return error(&format!("invalid character literal: \\{}", name)),
+    };
+    Ok(Str(c.to_string()))
+}
+
 pub fn read_str(str: String) -> MalRet {
-    let tokens = tokenize(&str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.len() == 0 {
-        return error("no input");
-    }
-    read_form(&mut Reader {
-        pos: 0,
-        tokens: tokens,
+    read_source(&str, None, ReadLimits::default())
+}
+
+// Reads a form from file (or other named source), so that the lists read
+// record where they came from
+pub fn read_source(str: &str, file: Option<&str>, limits: ReadLimits) -> MalRet {
+    match forms(str, file, limits)?.next() {
+        Some(form) => form,
+        None => error("no input"),
+    }
+}
+
+// Reads all the forms in str. An error says which form it is in.
+pub fn read_all(str: &str, file: Option<&str>, limits: ReadLimits) -> Result<Vec<MalVal>, MalErr> {
+    let mut forms = forms(str, file, limits)?;
+    let mut res = vec![];
+    while let Some(form) = forms.next() {
+        res.push(form.map_err(|e| forms.locate(e))?);
+    }
+    Ok(res)
+}
+
+// The forms in str, read one at a time. The input is tokenized up
+// front, but a form is only read when asked for, so the forms before a
+// malformed one can be used.
+pub fn forms<'a>(
+    str: &str,
+    file: Option<&'a str>,
+    limits: ReadLimits,
+) -> Result<Forms<'a>, MalErr> {
+    Ok(Forms {
+        rdr: Reader::new(str, file, None, limits)?,
+        count: 0,
+        line: 0,
     })
 }
+
+// count is the number of forms read so far, and line the line the last
+// one starts on
+#[derive(Debug)]
+pub struct Forms<'a> {
+    rdr: Reader<'a>,
+    count: usize,
+    line: i64,
+}
+
+impl<'a> Forms<'a> {
+    // Says where the form last read is in an error message, such as
+    // "lib.mal: form 3 (line 12): 'x' not found". Thrown values are left
+    // as they are, so they can still be caught.
+    pub fn locate(&self, e: MalErr) -> MalErr {
+        match e {
+            ErrString(s) => {
+                let file = self.rdr.file.map(|f| format!("{}: ", f));
+                ErrString(format!(
+                    "{}form {} (line {}): {}",
+                    file.unwrap_or_default(),
+                    self.count,
+                    self.line,
+                    s
+                ))
+            }
+            e => e,
+        }
+    }
+}
+
+// Iteration stops after an error, as the reader cannot tell where the
+// malformed form ends
+impl<'a> Iterator for Forms<'a> {
+    type Item = MalRet;
+
+    fn next(&mut self) -> Option<MalRet> {
+        let rdr = &mut self.rdr;
+        while rdr.pos < rdr.tokens.len() {
+            let line = rdr.positions[rdr.pos].0;
+            let form = match read_item(rdr) {
+                Ok(Item::Skip) => continue,
+                Ok(Item::Form(form)) => Ok(form),
+                Ok(Item::Splice(_)) => error("#?@ can only splice into a collection"),
+                Err(e) => Err(e),
+            };
+            self.count += 1;
+            self.line = line;
+            if form.is_err() {
+                rdr.pos = rdr.tokens.len();
+            }
+            return Some(form);
+        }
+        None
+    }
+}
+
+// Reads one EDN value. Empty input (or only discarded forms) reads as
+// nil, like Clojure's edn/read-string.
+pub fn read_edn(str: &str, tags: &TagHandlers, limits: ReadLimits) -> MalRet {
+    let mut forms = Forms {
+        rdr: Reader::new(str, None, Some(tags), limits)?,
+        count: 0,
+        line: 0,
+    };
+    forms.next().unwrap_or(Ok(Nil))
+}
@@ -12,7 +12,9 @@ use rustyline::Editor;
 #[allow(dead_code)]
 mod types;
 use crate::types::format_error;
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -31,7 +33,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match reader::read_str(line) {
                         Ok(mv) => {
                             println!("{}", mv.pr_str(true));
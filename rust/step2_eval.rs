@@ -17,8 +17,10 @@ use rustyline::Editor;
 mod types;
 use crate::types::MalErr::ErrString;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, func, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -53,7 +55,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::new(hm.kind());
             for (k, v) in hm.iter() {
                 new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
             }
@@ -66,12 +68,12 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
         List(l, _) => {
-            if l.len() == 0 {
+            if l.is_empty() {
                 return Ok(ast);
             }
             match eval_ast(&ast, &env)? {
                 List(ref el, _) => {
-                    let ref f = el[0].clone();
+                    let f = &el[0].clone();
                     f.apply(el[1..].to_vec())
                 }
                 _ => error("expected a list"),
@@ -118,7 +120,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match rep(&line, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
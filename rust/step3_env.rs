@@ -1,6 +1,5 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
@@ -17,9 +16,12 @@ use rustyline::Editor;
 #[allow(dead_code)]
 mod types;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, func, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
 mod env;
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
 
@@ -31,7 +33,7 @@ fn read(str: &str) -> MalRet {
 // eval
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
+        Sym(_) => Ok(env_get(env, ast)?),
         List(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
@@ -47,7 +49,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::new(hm.kind());
             for (k, v) in hm.iter() {
                 new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
             }
@@ -60,7 +62,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
         List(l, _) => {
-            if l.len() == 0 {
+            if l.is_empty() {
                 return Ok(ast);
             }
             let a0 = &l[0];
@@ -96,7 +98,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 }
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
-                        let ref f = el[0].clone();
+                        let f = &el[0].clone();
                         f.apply(el[1..].to_vec())
                     }
                     _ => error("expected a list"),
@@ -144,7 +146,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match rep(&line, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
@@ -1,6 +1,5 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
@@ -8,6 +7,8 @@ extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
 extern crate regex;
+extern crate serde;
+extern crate serde_json;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -16,13 +17,16 @@ use rustyline::Editor;
 #[macro_use]
 mod types;
 use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, FnInfo, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
 mod env;
 mod printer;
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
 #[macro_use]
 mod core;
+mod json;
+mod pprint;
 
 // read
 fn read(str: &str) -> MalRet {
@@ -32,7 +36,7 @@ fn read(str: &str) -> MalRet {
 // eval
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
+        Sym(_) => Ok(env_get(env, ast)?),
         List(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
@@ -48,7 +52,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::new(hm.kind());
             for (k, v) in hm.iter() {
                 new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
             }
@@ -61,7 +65,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
         List(l, _) => {
-            if l.len() == 0 {
+            if l.is_empty() {
                 return Ok(ast);
             }
             let a0 = &l[0];
@@ -111,17 +115,18 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 Sym(ref a0sym) if a0sym == "fn*" => {
                     let (a1, a2) = (l[1].clone(), l[2].clone());
                     Ok(MalFunc {
-                        eval: eval,
+                        eval,
                         ast: Rc::new(a2),
-                        env: env,
+                        env,
                         params: Rc::new(a1),
                         is_macro: false,
                         meta: Rc::new(Nil),
+                        info: Rc::new(FnInfo::new(&ast)),
                     })
                 }
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
-                        let ref f = el[0].clone();
+                        let f = &el[0].clone();
                         f.apply(el[1..].to_vec())
                     }
                     _ => error("expected a list"),
@@ -166,7 +171,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match rep(&line, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
@@ -1,6 +1,5 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
@@ -8,6 +7,8 @@ extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
 extern crate regex;
+extern crate serde;
+extern crate serde_json;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -16,13 +17,16 @@ use rustyline::Editor;
 #[macro_use]
 mod types;
 use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, FnInfo, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
 mod env;
 mod printer;
 mod reader;
 use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
 #[macro_use]
 mod core;
+mod json;
+mod pprint;
 
 // read
 fn read(str: &str) -> MalRet {
@@ -32,12 +36,12 @@ fn read(str: &str) -> MalRet {
 // eval
 fn quasiquote(ast: &MalVal) -> MalVal {
     match ast {
-        List(ref v, _) | Vector(ref v, _) if v.len() > 0 => {
+        List(ref v, _) | Vector(ref v, _) if !v.is_empty() => {
             let a0 = &v[0];
             match a0 {
                 Sym(ref s) if s == "unquote" => v[1].clone(),
                 _ => match a0 {
-                    List(ref v0, _) | Vector(ref v0, _) if v0.len() > 0 => match v0[0] {
+                    List(ref v0, _) | Vector(ref v0, _) if !v0.is_empty() => match v0[0] {
                         Sym(ref s) if s == "splice-unquote" => list![
                             Sym("concat".to_string()),
                             v0[1].clone(),
@@ -88,12 +92,12 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
         //println!("macroexpand 2: {:?}", ast);
         was_expanded = true;
     }
-    ((was_expanded, Ok(ast)))
+    (was_expanded, Ok(ast))
 }
 
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
+        Sym(_) => Ok(env_get(env, ast)?),
         List(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
@@ -109,7 +113,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::new(hm.kind());
             for (k, v) in hm.iter() {
                 new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
             }
@@ -125,7 +129,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
     'tco: loop {
         ret = match ast.clone() {
             List(l, _) => {
-                if l.len() == 0 {
+                if l.is_empty() {
                     return Ok(ast);
                 }
                 match macroexpand(ast.clone(), &env) {
@@ -137,7 +141,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     _ => (),
                 }
 
-                if l.len() == 0 {
+                if l.is_empty() {
                     return Ok(ast);
                 }
                 let a0 = &l[0];
@@ -186,17 +190,19 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                 ast,
                                 env,
                                 params,
+                                info,
                                 ..
                             } => Ok(env_set(
                                 &env,
                                 a1.clone(),
                                 MalFunc {
-                                    eval: eval,
+                                    eval,
                                     ast: ast.clone(),
                                     env: env.clone(),
                                     params: params.clone(),
                                     is_macro: true,
                                     meta: Rc::new(Nil),
+                                    info,
                                 },
                             )?),
                             _ => error("set_macro on non-function"),
@@ -235,12 +241,13 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     Sym(ref a0sym) if a0sym == "fn*" => {
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         Ok(MalFunc {
-                            eval: eval,
+                            eval,
                             ast: Rc::new(a2),
-                            env: env,
+                            env,
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            info: Rc::new(FnInfo::new(&ast)),
                         })
                     }
                     Sym(ref a0sym) if a0sym == "eval" => {
@@ -252,7 +259,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     }
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
-                            let ref f = el[0].clone();
+                            let f = &el[0].clone();
                             let args = el[1..].to_vec();
                             match f {
                                 Func(_, _) => f.apply(args),
@@ -338,7 +345,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match rep(&line, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
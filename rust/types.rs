@@ -1,12 +1,18 @@
+use fnv::FnvHashMap;
+use itertools::{Either, Itertools};
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufRead, Write};
 use std::rc::Rc;
-//use std::collections::HashMap;
-use fnv::FnvHashMap;
-use itertools::Itertools;
 
 use crate::env::{env_bind, Env};
 use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Stream, Sym, Tagged, Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
@@ -18,8 +24,8 @@ pub enum MalVal {
     Sym(String),
     List(Rc<Vec<MalVal>>, Rc<MalVal>),
     Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    Hash(Rc<MalMap>, Rc<MalVal>),
+    Func(Rc<NativeFn>, Rc<MalVal>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
         ast: Rc<MalVal>,
@@ -27,8 +33,93 @@ pub enum MalVal {
         params: Rc<MalVal>,
         is_macro: bool,
         meta: Rc<MalVal>,
+        info: Rc<FnInfo>,
     },
     Atom(Rc<RefCell<MalVal>>),
+    Stream(Rc<RefCell<MalStream>>),
+    // An EDN tagged literal such as #inst "1985-04-12T23:20:50Z": the
+    // tag (without the #) and the form it was applied to.
+    Tagged(String, Rc<MalVal>),
+}
+
+// A function implemented in Rust. f may be a closure, so a builtin
+// can carry its own state (e.g. the line editor of readline).
+pub struct NativeFn {
+    pub name: String,
+    pub f: Box<dyn Fn(MalArgs) -> MalRet>,
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NativeFn({:?})", self.name)
+    }
+}
+
+// What a MalFunc was defined by: the name def! or defmacro! gave it, its
+// source form (the fn* form, or the def! form once named) and the
+// position the reader recorded for that form.
+#[derive(Debug, Clone)]
+pub struct FnInfo {
+    pub name: Option<String>,
+    pub form: MalVal,
+    pub file: Option<String>,
+    pub line: Option<i64>,
+}
+
+impl FnInfo {
+    pub fn new(form: &MalVal) -> FnInfo {
+        let pos = |k: &str| match form {
+            List(_, meta) => match **meta {
+                Hash(ref hm, _) => hm.get(&format!("\u{29e}{}", k)).cloned(),
+                _ => None,
+            },
+            _ => None,
+        };
+        FnInfo {
+            name: None,
+            form: form.clone(),
+            file: match pos("file") {
+                Some(Str(f)) => Some(f),
+                _ => None,
+            },
+            line: match pos("line") {
+                Some(Int(l)) => Some(l),
+                _ => None,
+            },
+        }
+    }
+}
+
+// An open I/O handle. name is the path (or other description) the
+// stream was opened on, used when printing and in error values.
+// Stdin, Stdout and Stderr are the process streams bound to *in*,
+// *out* and *err*.
+pub enum MalStream {
+    Reader { name: String, rdr: Box<dyn BufRead> },
+    Writer { name: String, wtr: Box<dyn Write> },
+    Stdin,
+    Stdout,
+    Stderr,
+    Closed { name: String },
+}
+
+impl MalStream {
+    pub fn name(&self) -> &str {
+        match self {
+            MalStream::Reader { name, .. }
+            | MalStream::Writer { name, .. }
+            | MalStream::Closed { name } => name,
+            MalStream::Stdin => "*in*",
+            MalStream::Stdout => "*out*",
+            MalStream::Stderr => "*err*",
+        }
+    }
+}
+
+impl fmt::Debug for MalStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MalStream({:?})", self.name())
+    }
 }
 
 #[derive(Debug)]
@@ -37,28 +128,48 @@ pub enum MalErr {
     ErrMalVal(MalVal),
 }
 
+// Iteration order of a hash-map. Hash maps iterate in arbitrary order
+// (the printer sorts them), Sorted maps in key order and Ordered maps
+// in insertion order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapKind {
+    Hash,
+    Sorted,
+    Ordered,
+}
+
+#[derive(Debug, Clone)]
+pub struct MalMap {
+    kind: MapKind,
+    data: FnvHashMap<String, MalVal>,
+    // key order for Sorted and Ordered maps (unused for Hash)
+    order: Vec<String>,
+}
+
 pub type MalArgs = Vec<MalVal>;
 pub type MalRet = Result<MalVal, MalErr>;
 
 // type utility macros
 
+#[macro_export]
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    $crate::types::MalVal::List(::std::rc::Rc::new($seq),::std::rc::Rc::new($crate::types::MalVal::Nil))
   }};
   [$($args:expr),*] => {{
-    let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    let v: Vec<$crate::types::MalVal> = vec![$($args),*];
+    $crate::types::MalVal::List(::std::rc::Rc::new(v),::std::rc::Rc::new($crate::types::MalVal::Nil))
   }}
 }
 
+#[macro_export]
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    $crate::types::MalVal::Vector(::std::rc::Rc::new($seq),::std::rc::Rc::new($crate::types::MalVal::Nil))
   }};
   [$($args:expr),*] => {{
-    let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    let v: Vec<$crate::types::MalVal> = vec![$($args),*];
+    $crate::types::MalVal::Vector(::std::rc::Rc::new(v),::std::rc::Rc::new($crate::types::MalVal::Nil))
   }}
 }
 
@@ -79,6 +190,19 @@ pub fn atom(mv: &MalVal) -> MalVal {
     Atom(Rc::new(RefCell::new(mv.clone())))
 }
 
+thread_local! {
+    static GENSYM_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// A symbol that hasn't been used before: prefix followed by a number
+pub fn gensym(prefix: &str) -> MalVal {
+    let n = GENSYM_COUNT.with(|c| {
+        c.set(c.get() + 1);
+        c.get()
+    });
+    Sym(format!("{}{}", prefix, n))
+}
+
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
@@ -90,7 +214,8 @@ impl MalVal {
 
     pub fn empty_q(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            List(l, _) | Vector(l, _) => Ok(Bool(l.is_empty())),
+            Hash(hm, _) => Ok(Bool(hm.is_empty())),
             Nil => Ok(Bool(true)),
             _ => error("invalid type for empty?"),
         }
@@ -99,6 +224,7 @@ impl MalVal {
     pub fn count(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            Hash(hm, _) => Ok(Int(hm.len() as i64)),
             Nil => Ok(Int(0)),
             _ => error("invalid type for count"),
         }
@@ -106,7 +232,7 @@ impl MalVal {
 
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
-            Func(f, _) => f(args),
+            Func(ref nf, _) => (nf.f)(args),
             MalFunc {
                 eval,
                 ref ast,
@@ -124,10 +250,7 @@ impl MalVal {
     }
 
     pub fn keyword_q(&self) -> bool {
-        match self {
-            Str(s) if s.starts_with("\u{29e}") => true,
-            _ => false,
-        }
+        matches!(self, Str(s) if s.starts_with("\u{29e}"))
     }
 
     pub fn deref(&self) -> MalRet {
@@ -162,13 +285,46 @@ impl MalVal {
 
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
-            Func(_, meta) => Ok((&**meta).clone()),
-            MalFunc { meta, .. } => Ok((&**meta).clone()),
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((**meta).clone()),
+            Func(_, meta) => Ok((**meta).clone()),
+            MalFunc { meta, .. } => Ok((**meta).clone()),
             _ => error("meta not supported by type"),
         }
     }
 
+    // Names an anonymous function bound by the definition form def (a
+    // def! or defmacro!), which also becomes its source. Functions that
+    // already have a name keep it, so aliases print as the original.
+    pub fn named(self, name: &MalVal, def: &MalVal) -> MalVal {
+        match (self, name) {
+            (
+                MalFunc {
+                    eval,
+                    ast,
+                    env,
+                    params,
+                    is_macro,
+                    meta,
+                    info,
+                },
+                Sym(name),
+            ) if info.name.is_none() => MalFunc {
+                eval,
+                ast,
+                env,
+                params,
+                is_macro,
+                meta,
+                info: Rc::new(FnInfo {
+                    name: Some(name.to_string()),
+                    form: def.clone(),
+                    ..(*info).clone()
+                }),
+            },
+            (mv, _) => mv,
+        }
+    }
+
     pub fn with_meta(&mut self, new_meta: &MalVal) -> MalRet {
         match self {
             List(_, ref mut meta)
@@ -176,7 +332,7 @@ impl MalVal {
             | Hash(_, ref mut meta)
             | Func(_, ref mut meta)
             | MalFunc { ref mut meta, .. } => {
-                *meta = Rc::new((&*new_meta).clone());
+                *meta = Rc::new((*new_meta).clone());
             }
             _ => return error("with-meta not supported by type"),
         };
@@ -197,18 +353,199 @@ impl PartialEq for MalVal {
             | (List(ref a, _), Vector(ref b, _))
             | (Vector(ref a, _), List(ref b, _)) => a == b,
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
+            (Tagged(ref ta, ref a), Tagged(ref tb, ref b)) => ta == tb && a == b,
             (MalFunc { .. }, MalFunc { .. }) => false,
             _ => false,
         }
     }
 }
 
-pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
+// Rank of each type in the cross-type order used by compare
+fn type_rank(mv: &MalVal) -> u8 {
+    match mv {
+        Nil => 0,
+        Bool(_) => 1,
+        Int(_) => 2,
+        Str(_) if mv.keyword_q() => 4,
+        Str(_) => 3,
+        Sym(_) => 5,
+        List(_, _) | Vector(_, _) => 6,
+        Hash(_, _) => 7,
+        Tagged(_, _) => 8,
+        Atom(_) => 9,
+        Stream(_) => 10,
+        Func(_, _) => 11,
+        MalFunc { .. } => 12,
+    }
+}
+
+impl MalVal {
+    // Total order over all values:
+    //   nil < bool < numbers < strings < keywords < symbols
+    //       < sequences < hash-maps < tagged literals < atoms < streams
+    //       < native functions < mal functions
+    // Lists and vectors are compared lexicographically with each other,
+    // hash-maps lexicographically by their key-sorted entries, tagged
+    // literals by tag and then by form. Atoms, streams and functions have
+    // no meaningful order and compare by identity; a mal function by its
+    // body and then its closure environment, as closures share a body.
+    pub fn compare(&self, other: &MalVal) -> Ordering {
+        match (self, other) {
+            (Bool(a), Bool(b)) => a.cmp(b),
+            (Int(a), Int(b)) => a.cmp(b),
+            (Str(a), Str(b)) | (Sym(a), Sym(b)) if type_rank(self) == type_rank(other) => a.cmp(b),
+            (List(a, _), List(b, _))
+            | (List(a, _), Vector(b, _))
+            | (Vector(a, _), List(b, _))
+            | (Vector(a, _), Vector(b, _)) => compare_seqs(a, b),
+            (Hash(a, _), Hash(b, _)) => {
+                let (ea, eb) = (
+                    a.iter().sorted_by(|x, y| key_cmp(x.0, y.0)),
+                    b.iter().sorted_by(|x, y| key_cmp(x.0, y.0)),
+                );
+                for ((ka, va), (kb, vb)) in ea.zip(eb) {
+                    match key_cmp(ka, kb).then_with(|| va.compare(vb)) {
+                        Ordering::Equal => continue,
+                        o => return o,
+                    }
+                }
+                a.len().cmp(&b.len())
+            }
+            (Tagged(ta, a), Tagged(tb, b)) => ta.cmp(tb).then_with(|| a.compare(b)),
+            (Atom(a), Atom(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (Stream(a), Stream(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (Func(a, _), Func(b, _)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (
+                MalFunc {
+                    ast: a, env: ea, ..
+                },
+                MalFunc {
+                    ast: b, env: eb, ..
+                },
+            ) => Rc::as_ptr(a)
+                .cmp(&Rc::as_ptr(b))
+                .then_with(|| Rc::as_ptr(ea).cmp(&Rc::as_ptr(eb))),
+            _ => type_rank(self).cmp(&type_rank(other)),
+        }
+    }
+}
+
+fn compare_seqs(a: &[MalVal], b: &[MalVal]) -> Ordering {
+    for (x, y) in a.iter().zip(b.iter()) {
+        match x.compare(y) {
+            Ordering::Equal => continue,
+            o => return o,
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+pub fn func<F: Fn(MalArgs) -> MalRet + 'static>(f: F) -> MalVal {
+    named_func("", f)
+}
+
+pub fn named_func<F: Fn(MalArgs) -> MalRet + 'static>(name: &str, f: F) -> MalVal {
+    Func(
+        Rc::new(NativeFn {
+            name: name.to_string(),
+            f: Box::new(f),
+        }),
+        Rc::new(Nil),
+    )
+}
+
+// Order of hash-map keys (strings before keywords), matching compare
+pub fn key_cmp(a: &str, b: &str) -> Ordering {
+    let kw = |k: &str| k.starts_with('\u{29e}');
+    (kw(a), a).cmp(&(kw(b), b))
+}
+
+impl MalMap {
+    pub fn new(kind: MapKind) -> MalMap {
+        MalMap {
+            kind,
+            data: FnvHashMap::default(),
+            order: vec![],
+        }
+    }
+
+    pub fn kind(&self) -> MapKind {
+        self.kind
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get(&self, k: &str) -> Option<&MalVal> {
+        self.data.get(k)
+    }
+
+    // The entries in the order they are printed: plain hash-maps are
+    // sorted by key so the output is stable, the others keep their order
+    pub fn print_entries(&self) -> Vec<(&String, &MalVal)> {
+        let mut entries: Vec<(&String, &MalVal)> = self.iter().collect();
+        if self.kind == MapKind::Hash {
+            entries.sort_by(|a, b| key_cmp(a.0, b.0));
+        }
+        entries
+    }
+
+    pub fn contains_key(&self, k: &str) -> bool {
+        self.data.contains_key(k)
+    }
+
+    pub fn insert(&mut self, k: String, v: MalVal) {
+        if !self.data.contains_key(&k) {
+            match self.kind {
+                MapKind::Hash => (),
+                MapKind::Sorted => {
+                    let idx = self
+                        .order
+                        .binary_search_by(|o| key_cmp(o, &k))
+                        .unwrap_or_else(|i| i);
+                    self.order.insert(idx, k.clone());
+                }
+                MapKind::Ordered => self.order.push(k.clone()),
+            }
+        }
+        self.data.insert(k, v);
+    }
+
+    pub fn remove(&mut self, k: &str) {
+        if self.data.remove(k).is_some() && self.kind != MapKind::Hash {
+            self.order.retain(|o| o != k);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MalVal)> {
+        match self.kind {
+            MapKind::Hash => Either::Left(self.data.iter()),
+            _ => Either::Right(self.order.iter().map(move |k| (k, &self.data[k]))),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &MalVal> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl PartialEq for MalMap {
+    fn eq(&self, other: &MalMap) -> bool {
+        self.data == other.data
+    }
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
-    if kvs.len() % 2 != 0 {
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
+    if !kvs.len().is_multiple_of(2) {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
@@ -222,7 +559,7 @@ pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
     Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
     for k in ks.iter() {
         match k {
             Str(ref s) => {
@@ -235,6 +572,133 @@ pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+    _assoc(MalMap::new(MapKind::Hash), kvs)
+}
+
+pub fn sorted_map(kvs: MalArgs) -> MalRet {
+    _assoc(MalMap::new(MapKind::Sorted), kvs)
+}
+
+pub fn ordered_map(kvs: MalArgs) -> MalRet {
+    _assoc(MalMap::new(MapKind::Ordered), kvs)
+}
+
+// conversions between MalVal and Rust values
+
+impl From<()> for MalVal {
+    fn from(_: ()) -> MalVal {
+        Nil
+    }
+}
+
+impl From<bool> for MalVal {
+    fn from(b: bool) -> MalVal {
+        Bool(b)
+    }
+}
+
+impl From<i64> for MalVal {
+    fn from(i: i64) -> MalVal {
+        Int(i)
+    }
+}
+
+impl From<i32> for MalVal {
+    fn from(i: i32) -> MalVal {
+        Int(i as i64)
+    }
+}
+
+impl From<&str> for MalVal {
+    fn from(s: &str) -> MalVal {
+        Str(s.to_string())
+    }
+}
+
+impl From<String> for MalVal {
+    fn from(s: String) -> MalVal {
+        Str(s)
+    }
+}
+
+impl<T: Into<MalVal>> From<Option<T>> for MalVal {
+    fn from(o: Option<T>) -> MalVal {
+        o.map(Into::into).unwrap_or(Nil)
+    }
+}
+
+impl<T: Into<MalVal>> From<Vec<T>> for MalVal {
+    fn from(v: Vec<T>) -> MalVal {
+        vector!(v.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<MalVal>> From<HashMap<String, T>> for MalVal {
+    fn from(hm: HashMap<String, T>) -> MalVal {
+        let mut m = MalMap::new(MapKind::Hash);
+        for (k, v) in hm {
+            m.insert(k, v.into());
+        }
+        Hash(Rc::new(m), Rc::new(Nil))
+    }
+}
+
+fn conversion_error(mv: &MalVal, to: &str) -> MalErr {
+    ErrString(format!("cannot convert {} to {}", mv.pr_str(true), to))
+}
+
+impl TryFrom<MalVal> for bool {
+    type Error = MalErr;
+    fn try_from(mv: MalVal) -> Result<bool, MalErr> {
+        match mv {
+            Bool(b) => Ok(b),
+            _ => Err(conversion_error(&mv, "bool")),
+        }
+    }
+}
+
+impl TryFrom<MalVal> for i64 {
+    type Error = MalErr;
+    fn try_from(mv: MalVal) -> Result<i64, MalErr> {
+        match mv {
+            Int(i) => Ok(i),
+            _ => Err(conversion_error(&mv, "i64")),
+        }
+    }
+}
+
+impl TryFrom<MalVal> for String {
+    type Error = MalErr;
+    fn try_from(mv: MalVal) -> Result<String, MalErr> {
+        match mv {
+            Str(s) if !s.starts_with('\u{29e}') => Ok(s),
+            _ => Err(conversion_error(&mv, "String")),
+        }
+    }
+}
+
+impl<T: TryFrom<MalVal, Error = MalErr>> TryFrom<MalVal> for Vec<T> {
+    type Error = MalErr;
+    fn try_from(mv: MalVal) -> Result<Vec<T>, MalErr> {
+        match mv {
+            List(v, _) | Vector(v, _) => v.iter().cloned().map(T::try_from).collect(),
+            Nil => Ok(vec![]),
+            _ => Err(conversion_error(&mv, "Vec")),
+        }
+    }
+}
+
+// Keyword keys become their printed form (":kw")
+impl<T: TryFrom<MalVal, Error = MalErr>> TryFrom<MalVal> for HashMap<String, T> {
+    type Error = MalErr;
+    fn try_from(mv: MalVal) -> Result<HashMap<String, T>, MalErr> {
+        match mv {
+            Hash(hm, _) => hm
+                .iter()
+                .map(|(k, v)| Ok((Str(k.to_string()).pr_str(false), T::try_from(v.clone())?)))
+                .collect(),
+            Nil => Ok(HashMap::new()),
+            _ => Err(conversion_error(&mv, "HashMap")),
+        }
+    }
 }